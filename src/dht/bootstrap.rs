@@ -0,0 +1,24 @@
+/// Well-known routers we bootstrap from unless told otherwise.
+pub const DEFAULT_ROUTERS: &[&str] = &[
+	"router.bittorrent.com:6881",
+	"dht.transmissionbt.com:6881",
+	"router.utorrent.com:6881",
+	"dht.libtorrent.org:25401",
+];
+
+/// With fewer nodes than this in a routing table, we bootstrap it from the routers.
+pub const BOOTSTRAP_BELOW: usize = 8;
+
+/// Rounds of queries to the routers before giving up, if none of them answer.
+pub const BOOTSTRAP_ATTEMPTS: usize = 5;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum BootstrapStatus {
+	/// The routing table had enough nodes, so we didn't need to bootstrap.
+	#[default]
+	NotNeeded,
+	Running,
+	/// We found this many nodes.
+	Done(usize),
+	Failed(String),
+}
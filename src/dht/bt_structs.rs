@@ -11,6 +11,15 @@ static CONFIG: bincode::config::Configuration<
 	bincode::config::SkipFixedArrayLength,
 > = bincode::config::standard().skip_fixed_array_length();
 
+/// Our BEP 9 ut_metadata extension id, which peers use for the messages they send us.
+pub const UT_METADATA_ID: u8 = 2;
+
+/// Longest peer message we accept; a 16 KiB block fits, as does the bitfield of a torrent with
+/// a million pieces.
+pub const MAX_MESSAGE_LEN: usize = 1 << 17;
+
+const PROTOCOL: &[u8] = b"BitTorrent protocol";
+
 #[derive(Debug)]
 pub struct Handshake {
 	pub info_hash: [u8; 20],
@@ -18,6 +27,19 @@ pub struct Handshake {
 }
 
 impl Handshake {
+	pub const LEN: usize = 68;
+
+	/// Parse the other side's handshake, if it speaks BitTorrent.
+	pub fn from_bytes(buf: &[u8; Self::LEN]) -> Option<Self> {
+		if buf[0] as usize != PROTOCOL.len() || &buf[1..20] != PROTOCOL {
+			return None;
+		}
+		Some(Self {
+			info_hash: buf[28..48].try_into().unwrap(),
+			peer_id: buf[48..68].try_into().unwrap(),
+		})
+	}
+
 	pub fn to_bytes(&self) -> Vec<u8> {
 		#[derive(Debug, Encode)]
 		struct HandshakeInner {
@@ -27,7 +49,7 @@ impl Handshake {
 			peer_id: [u8; 20],
 		}
 
-		let mut out = bincode::encode_to_vec(
+		bincode::encode_to_vec(
 			HandshakeInner {
 				magic: "BitTorrent protocol",
 				reserved: [0, 0, 0, 0, 0, 0x10, 0, 0],
@@ -36,19 +58,7 @@ impl Handshake {
 			},
 			CONFIG,
 		)
-		.unwrap();
-
-		let ext = serde_bencode::to_bytes(&ExtensionHandshake::default()).unwrap();
-
-		let mut len: u32 = ext.len().try_into().unwrap();
-		len += 2;
-
-		out.extend_from_slice(&len.to_be_bytes());
-		out.push(20);
-		out.push(0);
-		out.extend_from_slice(&ext);
-
-		out
+		.unwrap()
 	}
 }
 
@@ -62,6 +72,11 @@ impl<'a> ExtensionHandshake {
 	pub fn from_bytes(buf: &'a [u8]) -> Result<Self, serde_bencode::Error> {
 		serde_bencode::de::from_bytes::<Self>(buf)
 	}
+
+	/// BEP 10: sent after the handshake, and after our bitfield if we send one.
+	pub fn to_message(&self) -> PeerMessage {
+		PeerMessage::Extended { id: 0, payload: serde_bencode::to_bytes(self).unwrap() }
+	}
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -71,6 +86,126 @@ pub struct MetadataExtension {
 
 impl Default for MetadataExtension {
 	fn default() -> Self {
-		Self { ut_metadata: Some(2) }
+		Self { ut_metadata: Some(UT_METADATA_ID) }
+	}
+}
+
+/// A BEP 3 peer wire message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerMessage {
+	KeepAlive,
+	Choke,
+	Unchoke,
+	Interested,
+	NotInterested,
+	Have {
+		piece: u32,
+	},
+	/// Which pieces the peer has, high bit of the first byte first.
+	Bitfield(Vec<u8>),
+	Request {
+		piece: u32,
+		offset: u32,
+		length: u32,
+	},
+	Piece {
+		piece: u32,
+		offset: u32,
+		data: Vec<u8>,
+	},
+	Cancel {
+		piece: u32,
+		offset: u32,
+		length: u32,
+	},
+	/// BEP 10: a message for extension `id`, where 0 is the extension handshake.
+	Extended {
+		id: u8,
+		payload: Vec<u8>,
+	},
+	/// A message type we don't know, e.g. from the fast extension; peers must ignore these.
+	Unknown {
+		id: u8,
+		payload: Vec<u8>,
+	},
+}
+
+impl PeerMessage {
+	/// The message with its length prefix.
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let u32s = |id: u8, values: &[u32]| {
+			let mut out = vec![id];
+			for value in values {
+				out.extend_from_slice(&value.to_be_bytes());
+			}
+			out
+		};
+		let body = match self {
+			Self::KeepAlive => Vec::new(),
+			Self::Choke => vec![0],
+			Self::Unchoke => vec![1],
+			Self::Interested => vec![2],
+			Self::NotInterested => vec![3],
+			Self::Have { piece } => u32s(4, &[*piece]),
+			Self::Bitfield(bitfield) => [&[5], bitfield.as_slice()].concat(),
+			Self::Request { piece, offset, length } => u32s(6, &[*piece, *offset, *length]),
+			Self::Piece { piece, offset, data } => [&u32s(7, &[*piece, *offset]), data.as_slice()].concat(),
+			Self::Cancel { piece, offset, length } => u32s(8, &[*piece, *offset, *length]),
+			Self::Extended { id, payload } => [&[20, *id], payload.as_slice()].concat(),
+			Self::Unknown { id, payload } => [&[*id], payload.as_slice()].concat(),
+		};
+		[&(body.len() as u32).to_be_bytes(), body.as_slice()].concat()
+	}
+
+	/// Parse a message without its length prefix.
+	pub fn from_bytes(buf: &[u8]) -> std::io::Result<Self> {
+		let invalid = |what| std::io::Error::new(std::io::ErrorKind::InvalidData, what);
+		let Some((&id, payload)) = buf.split_first() else { return Ok(Self::KeepAlive) };
+		let u32_at = |i: usize| u32::from_be_bytes(payload[i * 4..i * 4 + 4].try_into().unwrap());
+		let want_len = |len: usize| {
+			if payload.len() == len {
+				Ok(())
+			} else {
+				Err(invalid(format!("message {id} with {} byte payload", payload.len())))
+			}
+		};
+		Ok(match id {
+			0 => want_len(0).map(|_| Self::Choke)?,
+			1 => want_len(0).map(|_| Self::Unchoke)?,
+			2 => want_len(0).map(|_| Self::Interested)?,
+			3 => want_len(0).map(|_| Self::NotInterested)?,
+			4 => want_len(4).map(|_| Self::Have { piece: u32_at(0) })?,
+			5 => Self::Bitfield(payload.to_vec()),
+			6 => want_len(12).map(|_| Self::Request {
+				piece: u32_at(0),
+				offset: u32_at(1),
+				length: u32_at(2),
+			})?,
+			7 if payload.len() >= 8 => {
+				Self::Piece { piece: u32_at(0), offset: u32_at(1), data: payload[8..].to_vec() }
+			}
+			7 => Err(invalid("short piece message".into()))?,
+			8 => {
+				want_len(12).map(|_| Self::Cancel { piece: u32_at(0), offset: u32_at(1), length: u32_at(2) })?
+			}
+			20 => match payload.split_first() {
+				Some((&id, payload)) => Self::Extended { id, payload: payload.to_vec() },
+				None => Err(invalid("extended message without an id".into()))?,
+			},
+			id => Self::Unknown { id, payload: payload.to_vec() },
+		})
+	}
+
+	/// Read one length-prefixed message.
+	pub async fn read(rx: &mut (impl tokio::io::AsyncRead + Unpin)) -> std::io::Result<Self> {
+		use tokio::io::AsyncReadExt;
+		let len = rx.read_u32().await? as usize;
+		if len > MAX_MESSAGE_LEN {
+			let message = format!("{len} byte message");
+			return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message));
+		}
+		let mut buf = vec![0; len];
+		rx.read_exact(&mut buf).await?;
+		Self::from_bytes(&buf)
 	}
 }
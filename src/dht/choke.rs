@@ -0,0 +1,96 @@
+use rand::seq::IteratorRandom;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Peers unchoked for their rate, besides the optimistic unchoke.
+pub const UPLOAD_SLOTS: usize = 4;
+
+/// How often upload slots are handed out again.
+pub const CHOKE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Rounds an optimistic unchoke lasts before another peer gets a turn.
+const OPTIMISTIC_ROUNDS: u64 = 3;
+
+/// One connection as the choker sees it, shared with the connection.
+#[derive(Debug, Default)]
+pub struct ChokeSlot {
+	/// The peer wants pieces we have.
+	pub interested: AtomicBool,
+	/// The choker's decision, which the connection passes on to the peer.
+	pub unchoked: AtomicBool,
+	/// Block bytes the peer has sent us.
+	pub downloaded: AtomicU64,
+	/// Block bytes we've sent the peer.
+	pub uploaded: AtomicU64,
+}
+
+/// Decides which peers of a torrent may download from us (BEP 3).
+///
+/// Tit-for-tat: the interested peers that sent us the most since the last round get the upload
+/// slots, or while seeding, the ones we sent the most. One more slot goes to a random
+/// interested peer, so new peers get a chance to show what they can do.
+#[derive(Debug, Default)]
+pub struct Choker {
+	/// Each connection's slot, and its byte count at the last round.
+	slots: HashMap<u64, (Arc<ChokeSlot>, u64)>,
+	next_id: u64,
+	round: u64,
+	optimistic: Option<u64>,
+}
+
+impl Choker {
+	/// Register a connection; it starts out choked.
+	pub fn add(&mut self) -> (u64, Arc<ChokeSlot>) {
+		let id = self.next_id;
+		self.next_id += 1;
+		let slot = Arc::new(ChokeSlot::default());
+		self.slots.insert(id, (slot.clone(), 0));
+		(id, slot)
+	}
+
+	pub fn remove(&mut self, id: u64) {
+		self.slots.remove(&id);
+	}
+
+	/// Unchoke a newly interested peer right away if a slot is free, rather than at the next
+	/// round.
+	pub fn interested(&mut self, id: u64) {
+		let unchoked =
+			self.slots.values().filter(|(slot, _)| slot.unchoked.load(Ordering::Relaxed)).count();
+		if let Some((slot, _)) = self.slots.get(&id) {
+			if unchoked < UPLOAD_SLOTS + 1 {
+				slot.unchoked.store(true, Ordering::Relaxed);
+			}
+		}
+	}
+
+	/// Hand out the upload slots again; run every [`CHOKE_INTERVAL`].
+	pub fn rechoke(&mut self, seeding: bool) {
+		self.round += 1;
+
+		let mut rates = Vec::new();
+		for (&id, (slot, last)) in &mut self.slots {
+			let total = if seeding { &slot.uploaded } else { &slot.downloaded }.load(Ordering::Relaxed);
+			let rate = total.saturating_sub(std::mem::replace(last, total));
+			if slot.interested.load(Ordering::Relaxed) {
+				rates.push((rate, id));
+			}
+		}
+		rates.sort_unstable_by(|a, b| b.cmp(a));
+
+		let mut unchoked: HashSet<u64> = rates.iter().take(UPLOAD_SLOTS).map(|&(_, id)| id).collect();
+		let keep = self.optimistic.is_some_and(|id| {
+			rates.iter().any(|&(_, interested)| interested == id) && !unchoked.contains(&id)
+		});
+		if !keep || self.round.is_multiple_of(OPTIMISTIC_ROUNDS) {
+			let choked = rates.iter().map(|&(_, id)| id).filter(|id| !unchoked.contains(id));
+			self.optimistic = choked.choose(&mut rand::thread_rng());
+		}
+		unchoked.extend(self.optimistic);
+
+		for (id, (slot, _)) in &self.slots {
+			slot.unchoked.store(unchoked.contains(id), Ordering::Relaxed);
+		}
+	}
+}
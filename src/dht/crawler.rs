@@ -0,0 +1,159 @@
+use super::{ErrorReply, ResponseArgs};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Shortest wait between samples from one node, whatever `interval` it asks for.
+const MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// BEP 51 caps `interval` at six hours.
+const MAX_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Unanswered queries after which we forget a node.
+const MAX_FAILURES: u32 = 2;
+
+/// Most nodes the crawler keeps track of.
+const MAX_NODES: usize = 100_000;
+
+#[derive(Debug)]
+struct CrawlNode {
+	next_sample: Instant,
+	in_flight: bool,
+	failures: u32,
+	/// Size of the node's sample store, from its last `num`.
+	num: Option<i64>,
+	/// Set once the node has answered without samples, or told us it doesn't know the method.
+	unsupported: bool,
+}
+
+/// Schedule for a BEP 51 crawl: which nodes to ask for samples, and when.
+#[derive(Debug, Default)]
+pub struct Crawler {
+	nodes: HashMap<SocketAddr, CrawlNode>,
+	queue: VecDeque<SocketAddr>,
+}
+
+impl Crawler {
+	/// Add a node to crawl, if we don't know it already.
+	pub fn add(&mut self, addr: SocketAddr) {
+		if self.nodes.len() >= MAX_NODES || self.nodes.contains_key(&addr) {
+			return;
+		}
+		self.nodes.insert(
+			addr,
+			CrawlNode {
+				next_sample: Instant::now(),
+				in_flight: false,
+				failures: 0,
+				num: None,
+				unsupported: false,
+			},
+		);
+		self.queue.push_back(addr);
+	}
+
+	/// Up to `max` nodes whose interval has passed; they are marked as in flight.
+	pub fn due(&mut self, max: usize) -> Vec<SocketAddr> {
+		let now = Instant::now();
+		let mut due = Vec::new();
+		for _ in 0..self.queue.len() {
+			if due.len() >= max {
+				break;
+			}
+			let Some(addr) = self.queue.pop_front() else { break };
+			let Some(node) = self.nodes.get_mut(&addr) else { continue };
+			if node.next_sample > now {
+				self.queue.push_back(addr);
+				continue;
+			}
+			node.in_flight = true;
+			due.push(addr);
+		}
+		due
+	}
+
+	/// Schedule the node's next sample per its `interval`, and queue the nodes it returned.
+	pub fn on_response(&mut self, addr: SocketAddr, response: &ResponseArgs, ipv6: bool) {
+		if let Some(node) = self.nodes.get_mut(&addr) {
+			let interval = response.interval.unwrap_or_default().max(0) as u64;
+			node.in_flight = false;
+			node.failures = 0;
+			node.num = response.num.or(node.num);
+			node.unsupported = response.samples.is_none();
+			node.next_sample =
+				Instant::now() + Duration::from_secs(interval).clamp(MIN_INTERVAL, MAX_INTERVAL);
+			// Nodes without BEP 51 stay known, so we don't re-add them, but leave the queue.
+			if !node.unsupported {
+				self.queue.push_back(addr);
+			}
+		}
+		for (_, addr) in response.node_addrs(ipv6) {
+			self.add(addr);
+		}
+	}
+
+	/// Record that a node answered with an error. `204 Method Unknown` means it doesn't
+	/// support BEP 51, so we stop asking it; anything else counts as a failure.
+	pub fn on_error(&mut self, addr: SocketAddr, error: &ErrorReply) {
+		if error.code != 204 {
+			return self.on_failure(addr);
+		}
+		if let Some(node) = self.nodes.get_mut(&addr) {
+			node.in_flight = false;
+			node.unsupported = true;
+		}
+	}
+
+	/// Record that a node didn't answer. It is retried later, until it fails too often.
+	pub fn on_failure(&mut self, addr: SocketAddr) {
+		let Some(node) = self.nodes.get_mut(&addr) else { return };
+		node.in_flight = false;
+		node.failures += 1;
+		if node.failures >= MAX_FAILURES {
+			self.nodes.remove(&addr);
+		} else {
+			node.next_sample = Instant::now() + MIN_INTERVAL;
+			self.queue.push_back(addr);
+		}
+	}
+
+	pub fn in_flight(&self) -> usize {
+		self.nodes.values().filter(|n| n.in_flight).count()
+	}
+
+	pub fn len(&self) -> usize {
+		self.nodes.len()
+	}
+
+	/// Nodes that answered with samples.
+	pub fn supported(&self) -> usize {
+		self.nodes.values().filter(|n| n.num.is_some() && !n.unsupported).count()
+	}
+
+	/// Sum of the sample store sizes nodes have told us about.
+	pub fn total_num(&self) -> i64 {
+		self.nodes.values().filter_map(|n| n.num).sum()
+	}
+}
+
+/// Events per minute over a sliding one-minute window.
+#[derive(Debug, Default)]
+pub struct RatePerMinute {
+	events: VecDeque<(Instant, u64)>,
+}
+
+impl RatePerMinute {
+	pub fn record(&mut self, count: u64) {
+		if count > 0 {
+			self.events.push_back((Instant::now(), count));
+		}
+	}
+
+	pub fn per_minute(&mut self) -> u64 {
+		let window = Duration::from_secs(60);
+		while self.events.front().is_some_and(|(t, _)| t.elapsed() > window) {
+			self.events.pop_front();
+		}
+		self.events.iter().map(|(_, count)| count).sum()
+	}
+}
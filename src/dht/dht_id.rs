@@ -1,7 +1,7 @@
 use rand::prelude::*;
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
 
-pub fn id_from_ip(ip: &Ipv4Addr) -> [u8; 20] {
+pub fn id_from_ip(ip: &IpAddr) -> [u8; 20] {
 	let mut rng = thread_rng();
 	let r: u8 = rng.gen();
 
@@ -20,11 +20,57 @@ pub fn id_from_ip(ip: &Ipv4Addr) -> [u8; 20] {
 	bytes
 }
 
-fn magic_prefix_from_ip(ip: &Ipv4Addr, seed_r: u8) -> [u8; 3] {
-	let r32: u32 = seed_r.into();
-	let magic: u32 = 0x030f3fff;
-	let ip_int: u32 = u32::from_be_bytes(ip.octets());
-	let nonsense: u32 = (ip_int & magic) | (r32 << 29);
-	let crc: u32 = crc::Crc::<u32>::new(&crc::CRC_32_ISCSI).checksum(&nonsense.to_be_bytes());
+/// BEP 42: the CRC32-C of the masked IP, with the low 3 bits of `seed_r` mixed in.
+fn magic_prefix_from_ip(ip: &IpAddr, seed_r: u8) -> [u8; 3] {
+	let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISCSI);
+	let crc: u32 = match ip {
+		IpAddr::V4(ip) => {
+			let r32: u32 = seed_r.into();
+			let magic: u32 = 0x030f3fff;
+			let ip_int: u32 = u32::from_be_bytes(ip.octets());
+			let nonsense: u32 = (ip_int & magic) | (r32 << 29);
+			crc.checksum(&nonsense.to_be_bytes())
+		}
+		IpAddr::V6(ip) => {
+			let r64: u64 = seed_r.into();
+			let magic: u64 = 0x0103070f1f3f7fff;
+			let ip_int: u64 = u64::from_be_bytes(ip.octets()[..8].try_into().unwrap());
+			let nonsense: u64 = (ip_int & magic) | (r64 << 61);
+			crc.checksum(&nonsense.to_be_bytes())
+		}
+	};
 	crc.to_be_bytes()[..3].try_into().unwrap()
 }
+
+/// BEP 42: whether `id` was derived from `ip`. Nodes on local networks are exempt.
+pub fn verify_node_id(ip: &IpAddr, id: &[u8; 20]) -> bool {
+	let exempt = match ip {
+		IpAddr::V4(ip) => ip.is_private() || ip.is_link_local() || ip.is_loopback(),
+		IpAddr::V6(ip) => {
+			let first = ip.segments()[0];
+			ip.is_loopback() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+		}
+	};
+	if exempt {
+		return true;
+	}
+
+	let prefix = magic_prefix_from_ip(ip, id[19]);
+	id[0] == prefix[0] && id[1] == prefix[1] && id[2] & 0xf8 == prefix[2] & 0xf8
+}
+
+/// Which of `count` equal slices of the keyspace `id` falls in.
+pub fn keyspace_slice(id: &[u8; 20], count: usize) -> usize {
+	let top = u64::from_be_bytes(id[..8].try_into().unwrap());
+	((top as u128 * count as u128) >> 64) as usize
+}
+
+/// A random id in slice `slot` of `count` equal slices of the keyspace.
+pub fn id_in_slice(slot: usize, count: usize) -> [u8; 20] {
+	let start = ((slot as u128) << 64).div_ceil(count as u128);
+	let end = ((slot as u128 + 1) << 64).div_ceil(count as u128);
+	let top = thread_rng().gen_range(start..end) as u64;
+	let mut id: [u8; 20] = random();
+	id[..8].copy_from_slice(&top.to_be_bytes());
+	id
+}
@@ -1,6 +1,7 @@
 #[path = "serde_bytes_array.rs"]
 mod serde_bytes_array;
 
+use super::{BloomFilter, Item, RoutingNode};
 use bincode::{Decode, Encode};
 use log::*;
 use serde::{Deserialize, Serialize};
@@ -18,8 +19,8 @@ pub struct PingQuery {
 }
 
 impl PingQuery {
-	pub fn into_bytes(self) -> Vec<u8> {
-		Query { t: "0".into(), v: "XX01", y: "q", q: "ping", a: self }.to_bytes()
+	pub fn into_bytes(self, t: &[u8], ro: bool) -> Vec<u8> {
+		Query::new(t, "ping", self, ro).to_bytes()
 	}
 }
 
@@ -29,11 +30,14 @@ pub struct GetPeersQuery {
 	pub id: [u8; 20],
 	#[serde(with = "serde_bytes_array")]
 	pub info_hash: [u8; 20],
+	/// BEP 33: ask for `BFsd`/`BFpe` bloom filters.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub scrape: Option<u8>,
 }
 
 impl GetPeersQuery {
-	pub fn into_bytes(self) -> Vec<u8> {
-		Query { t: self.info_hash.into(), v: "XX01", y: "q", q: "get_peers", a: self }.to_bytes()
+	pub fn into_bytes(self, t: &[u8], ro: bool) -> Vec<u8> {
+		Query::new(t, "get_peers", self, ro).to_bytes()
 	}
 }
 
@@ -46,8 +50,8 @@ pub struct FindNodeQuery {
 }
 
 impl FindNodeQuery {
-	pub fn into_bytes(self) -> Vec<u8> {
-		Query { t: "0".into(), v: "XX01", y: "q", q: "find_node", a: self }.to_bytes()
+	pub fn into_bytes(self, t: &[u8], ro: bool) -> Vec<u8> {
+		Query::new(t, "find_node", self, ro).to_bytes()
 	}
 }
 
@@ -60,8 +64,97 @@ pub struct SampleInfohashesQuery {
 }
 
 impl SampleInfohashesQuery {
-	pub fn into_bytes(self) -> Vec<u8> {
-		Query { t: "0".into(), v: "XX01", y: "q", q: "sample_infohashes", a: self }.to_bytes()
+	pub fn into_bytes(self, t: &[u8], ro: bool) -> Vec<u8> {
+		Query::new(t, "sample_infohashes", self, ro).to_bytes()
+	}
+}
+
+#[derive(Debug, Serialize)]
+pub struct AnnouncePeerQuery {
+	#[serde(with = "serde_bytes_array")]
+	pub id: [u8; 20],
+	pub implied_port: u8,
+	#[serde(with = "serde_bytes_array")]
+	pub info_hash: [u8; 20],
+	pub port: u16,
+	/// BEP 33: we have the whole torrent.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub seed: Option<u8>,
+	#[serde(with = "serde_bytes")]
+	pub token: Vec<u8>,
+}
+
+impl AnnouncePeerQuery {
+	pub fn into_bytes(self, t: &[u8], ro: bool) -> Vec<u8> {
+		Query::new(t, "announce_peer", self, ro).to_bytes()
+	}
+}
+
+/// BEP 44 `get`; `seq` asks for a mutable item only if it's newer than that.
+#[derive(Debug, Serialize)]
+pub struct GetQuery {
+	#[serde(with = "serde_bytes_array")]
+	pub id: [u8; 20],
+	#[serde(with = "serde_bytes_array")]
+	pub target: [u8; 20],
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub seq: Option<i64>,
+}
+
+impl GetQuery {
+	pub fn into_bytes(self, t: &[u8], ro: bool) -> Vec<u8> {
+		Query::new(t, "get", self, ro).to_bytes()
+	}
+}
+
+/// BEP 44 `put` of an immutable or mutable item.
+#[derive(Debug, Serialize)]
+pub struct PutQuery {
+	#[serde(with = "serde_bytes_array")]
+	pub id: [u8; 20],
+	#[serde(with = "serde_bytes")]
+	pub token: Vec<u8>,
+	pub v: serde_bencode::value::Value,
+	#[serde(with = "serde_bytes", skip_serializing_if = "Option::is_none")]
+	pub k: Option<Vec<u8>>,
+	#[serde(with = "serde_bytes", skip_serializing_if = "Option::is_none")]
+	pub salt: Option<Vec<u8>>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub seq: Option<i64>,
+	#[serde(with = "serde_bytes", skip_serializing_if = "Option::is_none")]
+	pub sig: Option<Vec<u8>>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub cas: Option<i64>,
+}
+
+impl PutQuery {
+	/// A put of `item`, whose value has been parsed into `v`.
+	pub fn new(
+		id: [u8; 20],
+		token: Vec<u8>,
+		item: &Item,
+		v: serde_bencode::value::Value,
+		cas: Option<i64>,
+	) -> Self {
+		match item {
+			Item::Immutable { .. } => {
+				Self { id, token, v, k: None, salt: None, seq: None, sig: None, cas: None }
+			}
+			Item::Mutable { k, salt, seq, sig, .. } => Self {
+				id,
+				token,
+				v,
+				k: Some(k.to_vec()),
+				salt: (!salt.is_empty()).then(|| salt.clone()),
+				seq: Some(*seq),
+				sig: Some(sig.to_vec()),
+				cas,
+			},
+		}
+	}
+
+	pub fn into_bytes(self, t: &[u8], ro: bool) -> Vec<u8> {
+		Query::new(t, "put", self, ro).to_bytes()
 	}
 }
 
@@ -82,6 +175,32 @@ impl CompactInfo {
 	pub fn port(&self) -> u16 {
 		u16::from_be_bytes(self.port)
 	}
+	pub fn addr(&self) -> std::net::SocketAddr {
+		(std::net::Ipv4Addr::from(self.ip), self.port()).into()
+	}
+}
+
+/// BEP 32 compact node info for an IPv6 node.
+#[derive(Debug, Decode)]
+pub struct CompactInfo6 {
+	pub id: [u8; 20],
+	pub ip: [u8; 16],
+	port: [u8; 2],
+}
+
+impl CompactInfo6 {
+	pub fn host(&self) -> String {
+		self.addr().to_string()
+	}
+	pub fn ip_string(&self) -> String {
+		std::net::Ipv6Addr::from(self.ip).to_string()
+	}
+	pub fn port(&self) -> u16 {
+		u16::from_be_bytes(self.port)
+	}
+	pub fn addr(&self) -> std::net::SocketAddr {
+		(std::net::Ipv6Addr::from(self.ip), self.port()).into()
+	}
 }
 
 #[derive(Debug, Serialize)]
@@ -92,9 +211,17 @@ struct Query<T> {
 	y: &'static str,
 	q: &'static str,
 	a: T,
+	/// BEP 43: we're read-only and won't answer queries.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	ro: Option<u8>,
 }
 
 impl<T: Serialize> Query<T> {
+	/// `ro` marks the query as coming from a read-only node (BEP 43).
+	fn new(t: &[u8], q: &'static str, a: T, ro: bool) -> Self {
+		Query { t: t.into(), v: "XX01", y: "q", q, a, ro: ro.then_some(1) }
+	}
+
 	fn to_bytes(&self) -> Vec<u8> {
 		serde_bencode::to_bytes(&self).unwrap()
 	}
@@ -109,15 +236,28 @@ pub enum Peer {
 
 impl Peer {
 	pub fn host(&self) -> String {
-		format!("{}:{}", self.ip_string(), self.port())
+		self.addr().map_or_else(String::new, |addr| addr.to_string())
 	}
 	pub fn ip_string(&self) -> String {
-		let Peer::Peer(peer) = self;
-		format!("{}.{}.{}.{}", peer[0], peer[1], peer[2], peer[3])
+		self.addr().map_or_else(String::new, |addr| addr.ip().to_string())
 	}
 	pub fn port(&self) -> u16 {
+		self.addr().map_or(0, |addr| addr.port())
+	}
+	/// Compact peer info is 6 bytes for IPv4 and 18 bytes for IPv6 (BEP 32).
+	pub fn addr(&self) -> Option<std::net::SocketAddr> {
 		let Peer::Peer(peer) = self;
-		u16::from_be_bytes(peer[4..6].try_into().unwrap())
+		match peer.len() {
+			6 => {
+				let ip: [u8; 4] = peer[..4].try_into().unwrap();
+				Some((ip, u16::from_be_bytes([peer[4], peer[5]])).into())
+			}
+			18 => {
+				let ip: [u8; 16] = peer[..16].try_into().unwrap();
+				Some((ip, u16::from_be_bytes([peer[16], peer[17]])).into())
+			}
+			_ => None,
+		}
 	}
 }
 
@@ -141,10 +281,21 @@ pub struct ResponseArgs {
 	pub id: Vec<u8>,
 	pub token: Option<Bytes>,
 	pub nodes: Option<Bytes>,
+	pub nodes6: Option<Bytes>,
 	pub values: Option<Vec<Peer>>,
 	pub samples: Option<Bytes>,
 	pub interval: Option<i64>,
 	pub num: Option<i64>,
+	/// BEP 44 `get` results.
+	pub v: Option<serde_bencode::value::Value>,
+	pub k: Option<Bytes>,
+	pub seq: Option<i64>,
+	pub sig: Option<Bytes>,
+	/// BEP 33 scrape bloom filters of seeds and of peers.
+	#[serde(rename = "BFsd")]
+	pub bfsd: Option<Bytes>,
+	#[serde(rename = "BFpe")]
+	pub bfpe: Option<Bytes>,
 }
 
 impl ResponseArgs {
@@ -157,6 +308,231 @@ impl ResponseArgs {
 		let Bytes::Bytes(bytes) = self.nodes.as_ref().unwrap_or_default();
 		bytes.chunks_exact(26).map(|c| bincode::decode_from_slice(c, CONFIG).unwrap().0).collect()
 	}
+	pub fn nodes6(&self) -> Vec<CompactInfo6> {
+		let Bytes::Bytes(bytes) = self.nodes6.as_ref().unwrap_or_default();
+		bytes.chunks_exact(38).map(|c| bincode::decode_from_slice(c, CONFIG).unwrap().0).collect()
+	}
+	/// The BEP 44 item returned by a `get` for `target`, if it hashes and verifies correctly.
+	///
+	/// `salt` isn't sent back, so the caller passes the one it asked with.
+	pub fn item(&self, target: &[u8; 20], salt: &[u8]) -> Option<Item> {
+		let v = serde_bencode::to_bytes(self.v.as_ref()?).ok()?;
+		let item = match (&self.k, self.seq, &self.sig) {
+			(Some(Bytes::Bytes(k)), Some(seq), Some(Bytes::Bytes(sig))) => Item::Mutable {
+				k: k.as_slice().try_into().ok()?,
+				salt: salt.to_vec(),
+				seq,
+				sig: sig.as_slice().try_into().ok()?,
+				v,
+			},
+			_ => Item::Immutable { v },
+		};
+		(item.target() == *target && item.validate().is_ok()).then_some(item)
+	}
+	/// The BEP 33 seed and peer bloom filters, if the node sent either.
+	pub fn bloom_filters(&self) -> Option<(BloomFilter, BloomFilter)> {
+		let filter = |bytes: &Option<Bytes>| match bytes {
+			Some(Bytes::Bytes(bytes)) => BloomFilter::from_bytes(bytes),
+			None => None,
+		};
+		let (seeds, peers) = (filter(&self.bfsd), filter(&self.bfpe));
+		if seeds.is_none() && peers.is_none() {
+			return None;
+		}
+		Some((seeds.unwrap_or_default(), peers.unwrap_or_default()))
+	}
+	/// Ids and addresses of the returned nodes of one address family.
+	pub fn node_addrs(&self, ipv6: bool) -> Vec<([u8; 20], std::net::SocketAddr)> {
+		if ipv6 {
+			self.nodes6().iter().map(|n| (n.id, n.addr())).collect()
+		} else {
+			self.nodes().iter().map(|n| (n.id, n.addr())).collect()
+		}
+	}
+}
+
+/// Arguments of a query another node sent us; which ones are present depends on `q`.
+#[derive(Debug, Deserialize)]
+pub struct QueryArgs {
+	#[serde(with = "serde_bytes_array")]
+	pub id: [u8; 20],
+	pub target: Option<Bytes>,
+	pub info_hash: Option<Bytes>,
+	pub port: Option<u16>,
+	pub implied_port: Option<u8>,
+	pub token: Option<Bytes>,
+	/// BEP 32: which address families of nodes the querier wants, `n4` and/or `n6`.
+	pub want: Option<Vec<String>>,
+	/// BEP 44 `get` and `put` arguments.
+	pub v: Option<serde_bencode::value::Value>,
+	pub k: Option<Bytes>,
+	pub salt: Option<Bytes>,
+	pub seq: Option<i64>,
+	pub sig: Option<Bytes>,
+	pub cas: Option<i64>,
+	/// BEP 33 `get_peers` and `announce_peer` flags.
+	pub scrape: Option<u8>,
+	pub noseed: Option<u8>,
+	pub seed: Option<u8>,
+}
+
+impl QueryArgs {
+	pub fn target(&self) -> Option<[u8; 20]> {
+		let Bytes::Bytes(bytes) = self.target.as_ref()?;
+		bytes.as_slice().try_into().ok()
+	}
+	pub fn info_hash(&self) -> Option<[u8; 20]> {
+		let Bytes::Bytes(bytes) = self.info_hash.as_ref()?;
+		bytes.as_slice().try_into().ok()
+	}
+	/// The BEP 44 item in a `put`, or a 203 error if it's malformed. It isn't validated yet.
+	pub fn item(&self) -> Result<Item, ErrorReply> {
+		let malformed = |message: &str| ErrorReply { code: 203, message: message.into() };
+		let v = self.v.as_ref().ok_or_else(|| malformed("missing v"))?;
+		let v = serde_bencode::to_bytes(v).map_err(|_| malformed("bad v"))?;
+		let Some(Bytes::Bytes(k)) = &self.k else { return Ok(Item::Immutable { v }) };
+		let Bytes::Bytes(salt) = self.salt.as_ref().unwrap_or_default();
+		let Some(Bytes::Bytes(sig)) = &self.sig else { return Err(malformed("missing sig")) };
+		Ok(Item::Mutable {
+			k: k.as_slice().try_into().map_err(|_| malformed("bad k"))?,
+			salt: salt.clone(),
+			seq: self.seq.ok_or_else(|| malformed("missing seq"))?,
+			sig: sig.as_slice().try_into().map_err(|_| malformed("bad sig"))?,
+			v,
+		})
+	}
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IncomingQuery {
+	#[serde(with = "serde_bytes")]
+	pub t: Vec<u8>,
+	pub y: String,
+	pub q: String,
+	pub a: QueryArgs,
+	/// BEP 43: the querier is read-only.
+	pub ro: Option<u8>,
+}
+
+impl<'a> IncomingQuery {
+	pub fn from_bytes(buf: &'a [u8]) -> Result<Self, serde_bencode::Error> {
+		let query = serde_bencode::de::from_bytes::<Self>(buf)?;
+		if query.y != "q" {
+			return Err(serde_bencode::Error::Custom(format!("not a query: y = {:?}", query.y)));
+		}
+		Ok(query)
+	}
+}
+
+/// Our answer to an [`IncomingQuery`].
+#[derive(Debug, Default, Serialize)]
+pub struct ReplyArgs {
+	#[serde(with = "serde_bytes_array")]
+	pub id: [u8; 20],
+	#[serde(with = "serde_bytes", skip_serializing_if = "Option::is_none")]
+	pub nodes: Option<Vec<u8>>,
+	#[serde(with = "serde_bytes", skip_serializing_if = "Option::is_none")]
+	pub nodes6: Option<Vec<u8>>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub values: Option<Vec<serde_bytes::ByteBuf>>,
+	#[serde(with = "serde_bytes", skip_serializing_if = "Option::is_none")]
+	pub token: Option<Vec<u8>>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub v: Option<serde_bencode::value::Value>,
+	#[serde(with = "serde_bytes", skip_serializing_if = "Option::is_none")]
+	pub k: Option<Vec<u8>>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub seq: Option<i64>,
+	#[serde(with = "serde_bytes", skip_serializing_if = "Option::is_none")]
+	pub sig: Option<Vec<u8>>,
+	#[serde(rename = "BFsd", with = "serde_bytes", skip_serializing_if = "Option::is_none")]
+	pub bfsd: Option<Vec<u8>>,
+	#[serde(rename = "BFpe", with = "serde_bytes", skip_serializing_if = "Option::is_none")]
+	pub bfpe: Option<Vec<u8>>,
+}
+
+impl ReplyArgs {
+	/// Add a stored BEP 44 item to a `get` reply.
+	pub fn with_item(self, item: &Item) -> Self {
+		let v = serde_bencode::from_bytes(item.v()).ok();
+		match item {
+			Item::Immutable { .. } => Self { v, ..self },
+			Item::Mutable { k, seq, sig, .. } => {
+				Self { v, k: Some(k.to_vec()), seq: Some(*seq), sig: Some(sig.to_vec()), ..self }
+			}
+		}
+	}
+
+	pub fn into_bytes(self, t: Vec<u8>) -> Vec<u8> {
+		#[derive(Serialize)]
+		struct Reply {
+			#[serde(with = "serde_bytes")]
+			t: Vec<u8>,
+			v: &'static str,
+			y: &'static str,
+			r: ReplyArgs,
+		}
+
+		serde_bencode::to_bytes(&Reply { t, v: "XX01", y: "r", r: self }).unwrap()
+	}
+}
+
+/// A KRPC error reply, e.g. `203` for a protocol error or `204` for an unknown method.
+#[derive(Clone, Debug, Serialize)]
+pub struct ErrorReply {
+	pub code: i64,
+	pub message: String,
+}
+
+impl ErrorReply {
+	pub fn into_bytes(self, t: Vec<u8>) -> Vec<u8> {
+		#[derive(Serialize)]
+		struct Reply {
+			#[serde(with = "serde_bytes")]
+			t: Vec<u8>,
+			v: &'static str,
+			y: &'static str,
+			e: (i64, String),
+		}
+
+		serde_bencode::to_bytes(&Reply { t, v: "XX01", y: "e", e: (self.code, self.message) }).unwrap()
+	}
+}
+
+/// Encode nodes as BEP 5 compact node info, skipping any that aren't IPv4.
+pub fn compact_nodes<'a>(nodes: impl IntoIterator<Item = &'a RoutingNode>) -> Vec<u8> {
+	let mut out = Vec::new();
+	for node in nodes {
+		if let std::net::SocketAddr::V4(addr) = node.addr {
+			out.extend_from_slice(&node.id);
+			out.extend_from_slice(&addr.ip().octets());
+			out.extend_from_slice(&addr.port().to_be_bytes());
+		}
+	}
+	out
+}
+
+/// Encode nodes as BEP 32 compact node info, skipping any that aren't IPv6.
+pub fn compact_nodes6<'a>(nodes: impl IntoIterator<Item = &'a RoutingNode>) -> Vec<u8> {
+	let mut out = Vec::new();
+	for node in nodes {
+		if let std::net::SocketAddr::V6(addr) = node.addr {
+			out.extend_from_slice(&node.id);
+			out.extend_from_slice(&addr.ip().octets());
+			out.extend_from_slice(&addr.port().to_be_bytes());
+		}
+	}
+	out
+}
+
+/// Encode a peer as compact peer info: 6 bytes for IPv4, 18 bytes for IPv6.
+pub fn compact_peer(addr: &std::net::SocketAddr) -> serde_bytes::ByteBuf {
+	let mut out = match addr {
+		std::net::SocketAddr::V4(addr) => addr.ip().octets().to_vec(),
+		std::net::SocketAddr::V6(addr) => addr.ip().octets().to_vec(),
+	};
+	out.extend_from_slice(&addr.port().to_be_bytes());
+	serde_bytes::ByteBuf::from(out)
 }
 
 #[derive(Debug, Deserialize)]
@@ -165,13 +541,62 @@ pub struct Response {
 	pub t: Vec<u8>,
 	// y: &'static str,
 	// q: &'static str,
-	r: ResponseArgs,
+	pub r: ResponseArgs,
+	/// BEP 42: our address as the responder sees it.
+	pub ip: Option<Peer>,
 }
 
 impl<'a> Response {
-	pub fn from_bytes(buf: &'a [u8]) -> Result<ResponseArgs, serde_bencode::Error> {
-		let x = serde_bencode::de::from_bytes::<Response>(buf);
-		let x = x?;
-		Ok(x.r)
+	pub fn from_bytes(buf: &'a [u8]) -> Result<Self, serde_bencode::Error> {
+		serde_bencode::de::from_bytes::<Response>(buf)
+	}
+}
+
+/// An error reply to one of our queries.
+#[derive(Debug)]
+pub struct IncomingError {
+	pub t: Vec<u8>,
+	pub error: ErrorReply,
+	/// BEP 42: our address as the responder sees it.
+	pub ip: Option<Peer>,
+}
+
+impl<'a> IncomingError {
+	pub fn from_bytes(buf: &'a [u8]) -> Result<Self, serde_bencode::Error> {
+		#[derive(Deserialize)]
+		struct Error {
+			#[serde(with = "serde_bytes")]
+			t: Vec<u8>,
+			e: (i64, serde_bytes::ByteBuf),
+			ip: Option<Peer>,
+		}
+
+		let Error { t, e: (code, message), ip } = serde_bencode::de::from_bytes(buf)?;
+		let message = String::from_utf8_lossy(&message).into_owned();
+		Ok(Self { t, error: ErrorReply { code, message }, ip })
+	}
+}
+
+/// Any KRPC message, told apart by its `y` key.
+#[derive(Debug)]
+pub enum KrpcMessage {
+	Query(IncomingQuery),
+	Response(Response),
+	Error(IncomingError),
+}
+
+impl<'a> KrpcMessage {
+	pub fn from_bytes(buf: &'a [u8]) -> Result<Self, serde_bencode::Error> {
+		#[derive(Deserialize)]
+		struct Envelope {
+			y: String,
+		}
+
+		match serde_bencode::de::from_bytes::<Envelope>(buf)?.y.as_str() {
+			"q" => IncomingQuery::from_bytes(buf).map(Self::Query),
+			"r" => Response::from_bytes(buf).map(Self::Response),
+			"e" => IncomingError::from_bytes(buf).map(Self::Error),
+			y => Err(serde_bencode::Error::Custom(format!("unknown message type: y = {y:?}"))),
+		}
 	}
 }
@@ -0,0 +1,346 @@
+use super::{Bitfield, Choker, InfoDict, Storage};
+use log::*;
+use sha1::{Digest, Sha1};
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+
+/// Bytes we ask a peer for in one request.
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+
+/// Priority of files nobody has asked about. Priority 0 skips a file; pieces of higher
+/// priority files are always fetched before pieces of lower priority ones.
+pub const DEFAULT_PRIORITY: u8 = 1;
+
+/// A piece we've started fetching.
+#[derive(Debug)]
+struct PartialPiece {
+	data: Vec<u8>,
+	/// Per block, whether it has arrived.
+	received: Vec<bool>,
+	/// Per block, how many peers have been asked for it; more than one only in endgame.
+	requested: Vec<u8>,
+}
+
+impl PartialPiece {
+	/// The first block nobody has been asked for.
+	fn unrequested(&self) -> Option<usize> {
+		self.requested.iter().position(|&requested| requested == 0)
+	}
+}
+
+/// Which pieces we have, which blocks of the others are on their way, and which to ask for
+/// next: pieces of higher priority files first, then pieces we've started, then the pieces
+/// fewest of our peers have. Once every piece we want has been asked for, blocks still out are
+/// asked for again from other peers, so one slow peer doesn't hold up the end of a download.
+#[derive(Debug)]
+pub struct PiecePicker {
+	piece_length: u64,
+	total_length: u64,
+	have: Vec<bool>,
+	/// Per piece, how many connected peers have it.
+	availability: Vec<u32>,
+	/// Each file's offset and length in the torrent's bytes.
+	files: Vec<(u64, u64)>,
+	file_priorities: Vec<u8>,
+	/// Per piece, the highest priority of the files it overlaps.
+	priority: Vec<u8>,
+	/// Pieces we want and haven't started, best first.
+	queue: BTreeSet<(Reverse<u8>, u32, u32)>,
+	partial: HashMap<u32, PartialPiece>,
+}
+
+impl PiecePicker {
+	pub fn new(info: &InfoDict, have: Vec<bool>) -> Self {
+		let lengths = match &info.files {
+			Some(files) => files.iter().map(|file| file.length).collect(),
+			None => vec![info.total_length()],
+		};
+		let files = lengths
+			.iter()
+			.scan(0, |offset, &length| {
+				*offset += length;
+				Some((*offset - length, length))
+			})
+			.collect::<Vec<_>>();
+		let mut picker = Self {
+			piece_length: info.piece_length as u64,
+			total_length: info.total_length(),
+			availability: vec![0; have.len()],
+			priority: vec![DEFAULT_PRIORITY; have.len()],
+			file_priorities: vec![DEFAULT_PRIORITY; files.len()],
+			files,
+			have,
+			queue: BTreeSet::new(),
+			partial: HashMap::new(),
+		};
+		picker.requeue();
+		picker
+	}
+
+	fn key(&self, piece: u32) -> (Reverse<u8>, u32, u32) {
+		(Reverse(self.priority[piece as usize]), self.availability[piece as usize], piece)
+	}
+
+	fn wanted(&self, piece: u32) -> bool {
+		!self.have[piece as usize] && self.priority[piece as usize] > 0
+	}
+
+	/// Rebuild the queue from scratch, as after priorities change.
+	fn requeue(&mut self) {
+		self.queue = (0..self.have.len() as u32)
+			.filter(|&piece| self.wanted(piece) && !self.partial.contains_key(&piece))
+			.map(|piece| self.key(piece))
+			.collect();
+	}
+
+	/// Set the priority of each file, in torrent order; files past the end keep theirs.
+	pub fn set_file_priorities(&mut self, priorities: &[u8]) {
+		for (file, &priority) in self.file_priorities.iter_mut().zip(priorities) {
+			*file = priority;
+		}
+		self.priority.fill(0);
+		for (&(offset, length), &priority) in self.files.iter().zip(&self.file_priorities) {
+			if length == 0 {
+				continue;
+			}
+			let pieces = offset / self.piece_length..=(offset + length - 1) / self.piece_length;
+			for piece in pieces {
+				let piece = &mut self.priority[piece as usize];
+				*piece = (*piece).max(priority);
+			}
+		}
+		self.requeue();
+	}
+
+	/// Count `piece` as had by one more (`more`) or one fewer peer.
+	fn change_availability(&mut self, piece: u32, more: bool) {
+		let Some(&count) = self.availability.get(piece as usize) else { return };
+		let queued = self.queue.remove(&self.key(piece));
+		self.availability[piece as usize] = if more { count + 1 } else { count.saturating_sub(1) };
+		if queued {
+			self.queue.insert(self.key(piece));
+		}
+	}
+
+	/// A peer told us it has `piece`, with a `have` message.
+	pub fn peer_has(&mut self, piece: u32) {
+		self.change_availability(piece, true);
+	}
+
+	/// A peer told us which pieces it has, with a `bitfield` message.
+	pub fn peer_bitfield(&mut self, bitfield: &Bitfield) {
+		for piece in (0..self.have.len() as u32).filter(|&piece| bitfield.has(piece)) {
+			self.change_availability(piece, true);
+		}
+	}
+
+	/// A peer that had the pieces in `bitfield` disconnected.
+	pub fn peer_gone(&mut self, bitfield: &Bitfield) {
+		for piece in (0..self.have.len() as u32).filter(|&piece| bitfield.has(piece)) {
+			self.change_availability(piece, false);
+		}
+	}
+
+	pub fn num_pieces(&self) -> usize {
+		self.have.len()
+	}
+
+	pub fn has(&self, piece: u32) -> bool {
+		self.have.get(piece as usize).is_some_and(|&have| have)
+	}
+
+	/// Pieces we have of the ones we want, and how many we want.
+	pub fn progress(&self) -> (usize, usize) {
+		let wanted = (0..self.have.len()).filter(|&piece| self.priority[piece] > 0);
+		wanted.fold((0, 0), |(have, total), piece| (have + self.have[piece] as usize, total + 1))
+	}
+
+	/// Whether we have every piece we want.
+	pub fn is_complete(&self) -> bool {
+		let (have, total) = self.progress();
+		have == total
+	}
+
+	/// The pieces we have, to send to peers.
+	pub fn bitfield(&self) -> Bitfield {
+		let mut bitfield = Bitfield::from_bytes(vec![0; self.have.len().div_ceil(8)]);
+		for piece in (0..self.have.len()).filter(|&piece| self.have[piece]) {
+			bitfield.set(piece as u32);
+		}
+		bitfield
+	}
+
+	fn piece_len(&self, piece: u32) -> u32 {
+		(self.total_length - piece as u64 * self.piece_length).min(self.piece_length) as u32
+	}
+
+	fn block_len(&self, piece: u32, block: usize) -> u32 {
+		(self.piece_len(piece) - block as u32 * BLOCK_SIZE).min(BLOCK_SIZE)
+	}
+
+	/// The next block to ask a peer that has `peer_has` for, as piece, offset and length, and
+	/// count it as requested. `outstanding` are the requests the peer already has, which in
+	/// endgame aren't asked for twice.
+	pub fn next_request(
+		&mut self,
+		peer_has: &Bitfield,
+		outstanding: &[(u32, u32, u32)],
+	) -> Option<(u32, u32, u32)> {
+		let started = self
+			.partial
+			.iter()
+			.filter(|&(&piece, _)| self.priority[piece as usize] > 0 && peer_has.has(piece))
+			.filter_map(|(&piece, partial)| Some((self.key(piece), partial.unrequested()?)))
+			.min();
+		let fresh = self.queue.iter().find(|&&(_, _, piece)| peer_has.has(piece)).copied();
+
+		let (piece, block) = match (started, fresh) {
+			(Some((key, block)), fresh) if fresh.is_none_or(|fresh| key.0 <= fresh.0) => (key.2, block),
+			(_, Some(key)) => {
+				let piece = key.2;
+				self.queue.remove(&key);
+				let len = self.piece_len(piece) as usize;
+				let blocks = len.div_ceil(BLOCK_SIZE as usize);
+				let partial =
+					PartialPiece { data: vec![0; len], received: vec![false; blocks], requested: vec![0; blocks] };
+				self.partial.insert(piece, partial);
+				(piece, 0)
+			}
+			(_, None) if self.queue.is_empty() => self.endgame_block(peer_has, outstanding)?,
+			(_, None) => return None,
+		};
+		let requested = &mut self.partial.get_mut(&piece).unwrap().requested[block];
+		*requested = requested.saturating_add(1);
+		Some((piece, block as u32 * BLOCK_SIZE, self.block_len(piece, block)))
+	}
+
+	/// Everything we want has been asked for: the block still out to the fewest peers that
+	/// this peer has and hasn't been asked for.
+	fn endgame_block(
+		&self,
+		peer_has: &Bitfield,
+		outstanding: &[(u32, u32, u32)],
+	) -> Option<(u32, usize)> {
+		let asked = |piece, block| {
+			outstanding.iter().any(|&(p, offset, _)| (p, offset) == (piece, block as u32 * BLOCK_SIZE))
+		};
+		self
+			.partial
+			.iter()
+			.filter(|&(&piece, _)| self.priority[piece as usize] > 0 && peer_has.has(piece))
+			.flat_map(|(&piece, partial)| {
+				(0..partial.received.len())
+					.filter(move |&block| !partial.received[block])
+					.map(move |block| (partial.requested[block], piece, block))
+			})
+			.filter(|&(_, piece, block)| !asked(piece, block))
+			.min()
+			.map(|(_, piece, block)| (piece, block))
+	}
+
+	/// Whether a requested block no longer needs to arrive, because another peer sent it
+	/// first; requests for it should be cancelled.
+	pub fn is_done(&self, piece: u32, offset: u32) -> bool {
+		let block = (offset / BLOCK_SIZE) as usize;
+		self.partial.get(&piece).is_none_or(|partial| partial.received.get(block) != Some(&false))
+	}
+
+	/// Give back a request that won't be answered, e.g. because the peer choked us or left.
+	pub fn abandon(&mut self, piece: u32, offset: u32) {
+		let Some(partial) = self.partial.get_mut(&piece) else { return };
+		let block = (offset / BLOCK_SIZE) as usize;
+		if partial.received.get(block) == Some(&false) {
+			partial.requested[block] = partial.requested[block].saturating_sub(1);
+		}
+	}
+
+	/// Store a block. Once its piece has every block, the piece's data is returned to be
+	/// hashed, and [`Self::finished`] must be called with the result.
+	pub fn received(&mut self, piece: u32, offset: u32, data: &[u8]) -> Option<Vec<u8>> {
+		let block = (offset / BLOCK_SIZE) as usize;
+		let expected = offset.is_multiple_of(BLOCK_SIZE)
+			&& !self.is_done(piece, offset)
+			&& data.len() == self.block_len(piece, block) as usize;
+		if !expected {
+			debug!("unexpected block at {offset} of piece {piece}");
+			return None;
+		}
+		let partial = self.partial.get_mut(&piece).unwrap();
+		partial.data[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+		partial.received[block] = true;
+		partial.received.iter().all(|&received| received).then(|| std::mem::take(&mut partial.data))
+	}
+
+	/// Record whether a complete piece hashed correctly and was stored; if not, it is fetched
+	/// again from scratch.
+	pub fn finished(&mut self, piece: u32, stored: bool) {
+		self.partial.remove(&piece);
+		self.have[piece as usize] = stored;
+		if self.wanted(piece) {
+			self.queue.insert(self.key(piece));
+		}
+	}
+}
+
+/// A torrent being downloaded, shared by the connections feeding it.
+#[derive(Debug)]
+pub struct Download {
+	pub info_hash: [u8; 20],
+	/// The bencoded info dictionary, verified against `info_hash`, to serve over ut_metadata.
+	pub metadata: Vec<u8>,
+	pub info: InfoDict,
+	pub storage: Storage,
+	pub picker: std::sync::Mutex<PiecePicker>,
+	pub choker: std::sync::Mutex<Choker>,
+	/// Why the download can't go on, e.g. a piece couldn't be written out.
+	pub failed: std::sync::Mutex<Option<String>>,
+}
+
+impl Download {
+	/// Lay out the torrent under `dir`, and recheck whatever is already there. Blocks.
+	pub fn open(
+		info_hash: [u8; 20],
+		metadata: Vec<u8>,
+		info: InfoDict,
+		dir: &Path,
+	) -> std::io::Result<Self> {
+		let storage = Storage::new(dir, &info)?;
+		storage.create_empty_files()?;
+		let have = storage.recheck(&info);
+		let picker = std::sync::Mutex::new(PiecePicker::new(&info, have));
+		let (choker, failed) = Default::default();
+		Ok(Self { info_hash, metadata, info, storage, picker, choker, failed })
+	}
+
+	pub fn has(&self, piece: u32) -> bool {
+		self.picker.lock().unwrap().has(piece)
+	}
+
+	/// Hash a piece whose blocks have all arrived, and write it out if it matches. Blocks.
+	///
+	/// A piece that fails its hash check is fetched again; one that can't be written fails the
+	/// download, see [`Self::failed`].
+	pub fn store(&self, piece: u32, data: Vec<u8>) -> bool {
+		let valid = Sha1::digest(&data).as_slice() == self.info.piece_hash(piece as usize);
+		let stored = valid
+			&& match self.storage.write(self.info.piece_offset(piece as usize), &data) {
+				Ok(()) => true,
+				Err(e) => {
+					let mut failed = self.failed.lock().unwrap();
+					failed.get_or_insert_with(|| format!("could not write piece {piece}: {e}"));
+					false
+				}
+			};
+		if !valid {
+			info!("piece {piece} of {} failed its hash check", hex::encode(self.info_hash));
+		}
+		self.picker.lock().unwrap().finished(piece, stored);
+		stored
+	}
+
+	/// Pieces we have of the ones we want, and how many we want.
+	pub fn progress(&self) -> (usize, usize) {
+		self.picker.lock().unwrap().progress()
+	}
+}
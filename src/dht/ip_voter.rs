@@ -0,0 +1,45 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+
+/// Votes needed before we trust a reported external IP.
+const MIN_VOTES: usize = 5;
+
+/// Only the most recent votes count, so a changed IP eventually wins.
+const MAX_VOTES: usize = 50;
+
+/// Majority vote over the `ip` field that BEP 42 responders include in their replies.
+#[derive(Default)]
+pub struct IpVoter {
+	votes: VecDeque<(IpAddr, IpAddr)>,
+	consensus: Option<IpAddr>,
+}
+
+impl IpVoter {
+	/// Record that `voter` saw us as `reported`. Each voter has one vote.
+	///
+	/// Returns the new consensus if this vote changed it.
+	pub fn vote(&mut self, voter: IpAddr, reported: IpAddr) -> Option<IpAddr> {
+		self.votes.retain(|(v, _)| *v != voter);
+		self.votes.push_back((voter, reported));
+		if self.votes.len() > MAX_VOTES {
+			self.votes.pop_front();
+		}
+
+		let mut tally = HashMap::new();
+		for (_, ip) in &self.votes {
+			*tally.entry(*ip).or_insert(0) += 1;
+		}
+		let (ip, count) = tally.into_iter().max_by_key(|(_, count)| *count)?;
+
+		if count >= MIN_VOTES && count * 2 > self.votes.len() && self.consensus != Some(ip) {
+			self.consensus = Some(ip);
+			return Some(ip);
+		}
+
+		None
+	}
+
+	pub fn consensus(&self) -> Option<IpAddr> {
+		self.consensus
+	}
+}
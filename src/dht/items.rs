@@ -0,0 +1,151 @@
+use super::ErrorReply;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Largest bencoded `v` BEP 44 allows.
+pub const MAX_VALUE_LEN: usize = 1000;
+
+/// Largest `salt` BEP 44 allows.
+pub const MAX_SALT_LEN: usize = 64;
+
+/// Items that aren't put again within this long are dropped, per BEP 44's suggestion.
+const ITEM_EXPIRY: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// Most items we store for other nodes.
+const MAX_ITEMS: usize = 10_000;
+
+/// A BEP 44 data item. `v` is kept bencoded, exactly as it is hashed and signed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Item {
+	Immutable { v: Vec<u8> },
+	Mutable { k: [u8; 32], salt: Vec<u8>, seq: i64, sig: [u8; 64], v: Vec<u8> },
+}
+
+impl Item {
+	/// Where the item is stored: the SHA-1 of `v`, or of the public key and salt.
+	pub fn target(&self) -> [u8; 20] {
+		match self {
+			Item::Immutable { v } => immutable_target(v),
+			Item::Mutable { k, salt, .. } => mutable_target(k, salt),
+		}
+	}
+
+	pub fn v(&self) -> &[u8] {
+		match self {
+			Item::Immutable { v } | Item::Mutable { v, .. } => v,
+		}
+	}
+
+	pub fn seq(&self) -> Option<i64> {
+		match self {
+			Item::Immutable { .. } => None,
+			Item::Mutable { seq, .. } => Some(*seq),
+		}
+	}
+
+	/// Sign a mutable item with `key`.
+	pub fn mutable(key: &SigningKey, salt: Vec<u8>, seq: i64, v: Vec<u8>) -> Self {
+		let sig = key.sign(&signature_payload(&salt, seq, &v)).to_bytes();
+		Item::Mutable { k: key.verifying_key().to_bytes(), salt, seq, sig, v }
+	}
+
+	/// Check the size limits and, for mutable items, the signature, as a `put` must.
+	pub fn validate(&self) -> Result<(), ErrorReply> {
+		if self.v().len() > MAX_VALUE_LEN {
+			return Err(ErrorReply { code: 205, message: "message (v field) too big".into() });
+		}
+		let Item::Mutable { k, salt, seq, sig, v } = self else { return Ok(()) };
+		if salt.len() > MAX_SALT_LEN {
+			return Err(ErrorReply { code: 207, message: "salt (salt field) too big".into() });
+		}
+		let valid = VerifyingKey::from_bytes(k)
+			.and_then(|k| k.verify(&signature_payload(salt, *seq, v), &Signature::from_bytes(sig)));
+		if valid.is_err() {
+			return Err(ErrorReply { code: 206, message: "invalid signature".into() });
+		}
+		Ok(())
+	}
+}
+
+pub fn immutable_target(v: &[u8]) -> [u8; 20] {
+	Sha1::digest(v).into()
+}
+
+pub fn mutable_target(k: &[u8; 32], salt: &[u8]) -> [u8; 20] {
+	let mut hasher = Sha1::new();
+	hasher.update(k);
+	hasher.update(salt);
+	hasher.finalize().into()
+}
+
+/// What a mutable item's signature covers: the bencoded `salt`, `seq` and `v` entries.
+fn signature_payload(salt: &[u8], seq: i64, v: &[u8]) -> Vec<u8> {
+	let mut payload = Vec::new();
+	if !salt.is_empty() {
+		payload.extend_from_slice(format!("4:salt{}:", salt.len()).as_bytes());
+		payload.extend_from_slice(salt);
+	}
+	payload.extend_from_slice(format!("3:seqi{seq}e1:v").as_bytes());
+	payload.extend_from_slice(v);
+	payload
+}
+
+/// Items other nodes have put to us.
+#[derive(Default)]
+pub struct ItemStore {
+	items: HashMap<[u8; 20], (Item, Instant)>,
+}
+
+impl ItemStore {
+	/// Store a validated item. `cas` is the `seq` the putter expects us to have.
+	pub fn put(&mut self, item: Item, cas: Option<i64>) -> Result<(), ErrorReply> {
+		let target = item.target();
+		if let Some((stored, stored_at)) = self.items.get_mut(&target) {
+			if let (Some(cas), Some(seq)) = (cas, stored.seq()) {
+				if cas != seq {
+					return Err(ErrorReply { code: 301, message: "CAS mismatch".into() });
+				}
+			}
+			match (item.seq(), stored.seq()) {
+				(Some(new), Some(old)) if new < old => {
+					return Err(ErrorReply { code: 302, message: "sequence number less than current".into() });
+				}
+				(Some(new), Some(old)) if new == old => {}
+				_ => *stored = item,
+			}
+			*stored_at = Instant::now();
+			return Ok(());
+		}
+
+		if self.items.len() >= MAX_ITEMS {
+			self.expire();
+			if self.items.len() >= MAX_ITEMS {
+				return Err(ErrorReply { code: 202, message: "storage full".into() });
+			}
+		}
+		self.items.insert(target, (item, Instant::now()));
+		Ok(())
+	}
+
+	/// The item stored at `target`, unless the requester already has `seq` or newer.
+	pub fn get(&self, target: &[u8; 20], seq: Option<i64>) -> Option<&Item> {
+		let (item, stored_at) = self.items.get(target)?;
+		if stored_at.elapsed() >= ITEM_EXPIRY {
+			return None;
+		}
+		match (seq, item.seq()) {
+			(Some(seq), Some(stored)) if stored <= seq => None,
+			_ => Some(item),
+		}
+	}
+
+	pub fn expire(&mut self) {
+		self.items.retain(|_, (_, stored_at)| stored_at.elapsed() < ITEM_EXPIRY);
+	}
+
+	pub fn len(&self) -> usize {
+		self.items.len()
+	}
+}
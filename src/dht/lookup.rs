@@ -0,0 +1,278 @@
+use super::{verify_node_id, xor_distance, Bytes, NodeIdPolicy, ResponseArgs, RoutingNode, K};
+use std::net::SocketAddr;
+
+/// Number of queries a lookup keeps in flight at once.
+pub const ALPHA: usize = 3;
+
+/// How many candidates a lookup remembers; anything further away is dropped.
+const MAX_CANDIDATES: usize = K * 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LookupKind {
+	FindNode,
+	GetPeers,
+	SampleInfohashes,
+	/// BEP 44 `get`, which also collects write tokens for `put`.
+	Get,
+	/// BEP 33 `get_peers` with `scrape=1`.
+	Scrape,
+}
+
+/// A node that answered a lookup, with the write token it handed out, if any.
+#[derive(Clone, Debug)]
+pub struct LookupNode {
+	pub id: [u8; 20],
+	pub addr: SocketAddr,
+	pub token: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum CandidateState {
+	Unqueried,
+	InFlight,
+	Responded(Option<Vec<u8>>),
+	Failed,
+}
+
+#[derive(Debug)]
+struct Candidate {
+	id: [u8; 20],
+	addr: SocketAddr,
+	distance: [u8; 20],
+	state: CandidateState,
+}
+
+/// State of an alpha-parallel iterative lookup for the K nodes closest to a target.
+///
+/// The lookup is finished once the K closest nodes that haven't failed have all
+/// answered, i.e. the closest set has stopped improving.
+#[derive(Debug)]
+pub struct Lookup {
+	target: [u8; 20],
+	kind: LookupKind,
+	ipv6: bool,
+	policy: NodeIdPolicy,
+	candidates: Vec<Candidate>,
+}
+
+impl Lookup {
+	/// Start a lookup over the IPv4 or IPv6 DHT, seeded from that family's routing table.
+	///
+	/// Under [`NodeIdPolicy::Require`], nodes with forged ids are never queried.
+	pub fn new(
+		target: [u8; 20],
+		kind: LookupKind,
+		ipv6: bool,
+		policy: NodeIdPolicy,
+		seeds: Vec<RoutingNode>,
+	) -> Self {
+		let mut lookup = Self { target, kind, ipv6, policy, candidates: Vec::new() };
+		for node in seeds {
+			lookup.add_candidate(node.id, node.addr);
+		}
+		lookup
+	}
+
+	pub fn target(&self) -> [u8; 20] {
+		self.target
+	}
+
+	pub fn kind(&self) -> LookupKind {
+		self.kind
+	}
+
+	fn add_candidate(&mut self, id: [u8; 20], addr: SocketAddr) {
+		if self.candidates.iter().any(|c| c.id == id || c.addr == addr) {
+			return;
+		}
+		if self.policy == NodeIdPolicy::Require && !verify_node_id(&addr.ip(), &id) {
+			return;
+		}
+		let distance = xor_distance(&id, &self.target);
+		let index = self.candidates.partition_point(|c| c.distance < distance);
+		if self.candidates.len() >= MAX_CANDIDATES {
+			// Make room by forgetting the furthest node we aren't waiting on or holding an answer from.
+			let evictable =
+				|c: &Candidate| matches!(c.state, CandidateState::Unqueried | CandidateState::Failed);
+			let Some(furthest) = self.candidates[index..].iter().rposition(evictable) else { return };
+			self.candidates.remove(index + furthest);
+		}
+		self.candidates.insert(index, Candidate { id, addr, distance, state: CandidateState::Unqueried });
+	}
+
+	/// The K closest candidates that haven't failed.
+	fn closest_live(&self) -> impl Iterator<Item = &Candidate> {
+		self.candidates.iter().filter(|c| c.state != CandidateState::Failed).take(K)
+	}
+
+	fn in_flight(&self) -> usize {
+		self.candidates.iter().filter(|c| c.state == CandidateState::InFlight).count()
+	}
+
+	/// Nodes to query next, keeping at most `parallelism` in flight; they are marked as in flight.
+	///
+	/// This is normally [`ALPHA`], but less when we're sending faster than we're allowed to.
+	pub fn next_queries(&mut self, parallelism: usize) -> Vec<SocketAddr> {
+		let budget = parallelism.saturating_sub(self.in_flight());
+		let ids = self
+			.closest_live()
+			.filter(|c| c.state == CandidateState::Unqueried)
+			.take(budget)
+			.map(|c| c.id)
+			.collect::<Vec<_>>();
+		self
+			.candidates
+			.iter_mut()
+			.filter(|c| ids.contains(&c.id))
+			.map(|c| {
+				c.state = CandidateState::InFlight;
+				c.addr
+			})
+			.collect()
+	}
+
+	/// Feed a response into the lookup. Returns `false` if it wasn't from a node we queried.
+	pub fn on_response(&mut self, addr: SocketAddr, response: &ResponseArgs) -> bool {
+		let Some(candidate) = self.candidates.iter_mut().find(|c| c.addr == addr) else {
+			return false;
+		};
+		if candidate.state != CandidateState::InFlight {
+			return false;
+		}
+		let Bytes::Bytes(token) = response.token.as_ref().unwrap_or_default();
+		candidate.state = CandidateState::Responded((!token.is_empty()).then(|| token.clone()));
+
+		for (id, addr) in response.node_addrs(self.ipv6) {
+			self.add_candidate(id, addr);
+		}
+
+		true
+	}
+
+	/// Record that a node we queried didn't answer.
+	pub fn on_failure(&mut self, addr: SocketAddr) {
+		if let Some(candidate) = self.candidates.iter_mut().find(|c| c.addr == addr) {
+			if candidate.state == CandidateState::InFlight {
+				candidate.state = CandidateState::Failed;
+			}
+		}
+	}
+
+	pub fn is_done(&self) -> bool {
+		self.closest_live().all(|c| matches!(c.state, CandidateState::Responded(_)))
+	}
+
+	pub fn queried(&self) -> usize {
+		self.candidates.iter().filter(|c| c.state != CandidateState::Unqueried).count()
+	}
+
+	pub fn responded(&self) -> usize {
+		self.candidates.iter().filter(|c| matches!(c.state, CandidateState::Responded(_))).count()
+	}
+
+	/// The K closest nodes that answered, with their write tokens.
+	pub fn closest(&self) -> Vec<LookupNode> {
+		self
+			.candidates
+			.iter()
+			.filter_map(|c| match &c.state {
+				CandidateState::Responded(token) => {
+					Some(LookupNode { id: c.id, addr: c.addr, token: token.clone() })
+				}
+				_ => None,
+			})
+			.take(K)
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A node `n` away from the all-zero target.
+	fn node(n: u16) -> ([u8; 20], SocketAddr) {
+		let mut id = [0; 20];
+		id[18..].copy_from_slice(&n.to_be_bytes());
+		(id, SocketAddr::from(([10, 0, (n >> 8) as u8, n as u8], 6881)))
+	}
+
+	fn lookup(seeds: &[u16]) -> Lookup {
+		let mut lookup = Lookup::new([0; 20], LookupKind::FindNode, false, NodeIdPolicy::Ignore, vec![]);
+		for &n in seeds {
+			let (id, addr) = node(n);
+			lookup.add_candidate(id, addr);
+		}
+		lookup
+	}
+
+	/// A response from node `from` telling us about `nodes`.
+	fn response(from: u16, nodes: &[u16]) -> ResponseArgs {
+		let compact: Vec<u8> = nodes
+			.iter()
+			.flat_map(|&n| {
+				let (id, addr) = node(n);
+				let SocketAddr::V4(addr) = addr else { unreachable!() };
+				[&id[..], &addr.ip().octets(), &addr.port().to_be_bytes()].concat()
+			})
+			.collect();
+		let nodes = format!("5:nodes{}:", compact.len());
+		let id = node(from).0;
+		let message = [&b"d2:id20:"[..], &id, nodes.as_bytes(), &compact, b"e"].concat();
+		serde_bencode::from_bytes(&message).unwrap()
+	}
+
+	fn ids(nodes: &[LookupNode]) -> Vec<[u8; 20]> {
+		nodes.iter().map(|node| node.id).collect()
+	}
+
+	#[test]
+	fn lookups_end_once_the_closest_live_nodes_answer() {
+		let mut lookup = lookup(&[10, 11, 12]);
+		let queried = lookup.next_queries(ALPHA);
+		assert_eq!(queried, [node(10).1, node(11).1, node(12).1]);
+		assert!(lookup.next_queries(ALPHA).is_empty());
+
+		assert!(lookup.on_response(node(10).1, &response(10, &[1, 2])));
+		assert!(!lookup.on_response(node(10).1, &response(10, &[])), "answered twice");
+		assert!(!lookup.on_response(node(99).1, &response(99, &[])), "never queried");
+		lookup.on_failure(node(11).1);
+		assert!(!lookup.is_done());
+
+		// Node 12 is still in flight, so only two more go out.
+		assert_eq!(lookup.next_queries(ALPHA), [node(1).1, node(2).1]);
+		for n in [1, 2] {
+			assert!(lookup.on_response(node(n).1, &response(n, &[])));
+		}
+		assert!(!lookup.is_done());
+		assert!(lookup.on_response(node(12).1, &response(12, &[])));
+		assert!(lookup.is_done());
+		assert_eq!(ids(&lookup.closest()), [node(1).0, node(2).0, node(10).0, node(12).0]);
+	}
+
+	#[test]
+	fn only_unqueried_and_failed_candidates_are_evicted() {
+		let far: Vec<u16> = (100..100 + MAX_CANDIDATES as u16).collect();
+		let mut lookup = lookup(&far);
+		for candidate in &mut lookup.candidates[..MAX_CANDIDATES / 2] {
+			candidate.state = CandidateState::InFlight;
+		}
+		lookup.candidates[MAX_CANDIDATES / 2].state = CandidateState::Failed;
+
+		// Closer nodes push out the furthest unqueried ones, then the failed one.
+		for n in 1..=(MAX_CANDIDATES / 2) as u16 {
+			let (id, addr) = node(n);
+			lookup.add_candidate(id, addr);
+			assert_eq!(lookup.candidates.len(), MAX_CANDIDATES);
+		}
+		let in_flight = lookup.candidates.iter().filter(|c| c.state == CandidateState::InFlight);
+		assert_eq!(in_flight.count(), MAX_CANDIDATES / 2);
+		assert!(lookup.candidates.iter().all(|c| c.state != CandidateState::Failed));
+
+		// Only nodes in flight are further away, so this one is turned away.
+		let (id, addr) = node(50);
+		lookup.add_candidate(id, addr);
+		assert!(lookup.candidates.iter().all(|c| c.id != id));
+		assert!(lookup.candidates.windows(2).all(|pair| pair[0].distance < pair[1].distance));
+	}
+}
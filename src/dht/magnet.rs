@@ -0,0 +1,54 @@
+use serde::{Deserialize, Serialize};
+
+/// A BEP 46 `magnet:?xs=urn:btpk:<public key>&s=<salt>` link, pointing at a mutable item
+/// that holds a torrent's current infohash.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MutableMagnet {
+	pub k: [u8; 32],
+	pub salt: Vec<u8>,
+}
+
+impl MutableMagnet {
+	pub fn parse(uri: &str) -> Option<Self> {
+		let query = uri.strip_prefix("magnet:?")?;
+		let mut k = None;
+		let mut salt = Vec::new();
+		for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+			match key {
+				"xs" => {
+					if let Some(key) = value.strip_prefix("urn:btpk:") {
+						k = hex::decode(key).ok()?.try_into().ok();
+					}
+				}
+				"s" => salt = hex::decode(value).ok()?,
+				_ => {}
+			}
+		}
+		Some(Self { k: k?, salt })
+	}
+
+	pub fn to_uri(&self) -> String {
+		let mut uri = format!("magnet:?xs=urn:btpk:{}", hex::encode(self.k));
+		if !self.salt.is_empty() {
+			uri += &format!("&s={}", hex::encode(&self.salt));
+		}
+		uri
+	}
+}
+
+/// The value of a BEP 46 mutable item: `{"ih": <infohash>}`.
+#[derive(Debug, Deserialize, Serialize)]
+struct Pointer {
+	#[serde(with = "serde_bytes")]
+	ih: Vec<u8>,
+}
+
+/// Bencode the item value pointing at `info_hash`.
+pub fn pointer_value(info_hash: [u8; 20]) -> Vec<u8> {
+	serde_bencode::to_bytes(&Pointer { ih: info_hash.to_vec() }).unwrap()
+}
+
+/// The infohash a mutable item's bencoded value points at.
+pub fn pointer_infohash(v: &[u8]) -> Option<[u8; 20]> {
+	serde_bencode::from_bytes::<Pointer>(v).ok()?.ih.try_into().ok()
+}
@@ -1,6 +1,9 @@
+use super::PeerMessage;
+use log::*;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use std::usize::MAX;
 use tokio::sync::Mutex;
 use turbosql::*;
 
@@ -24,6 +27,22 @@ impl MetadataMessage {
 
 		out
 	}
+
+	/// Our answer to a peer's request for `piece` of `metadata`: the piece, or a reject if
+	/// there's no such piece.
+	pub fn reply(metadata: &[u8], piece: usize, extension_id: u8) -> PeerMessage {
+		let start = piece.saturating_mul(METADATA_PIECE_LEN);
+		let end = start.saturating_add(METADATA_PIECE_LEN).min(metadata.len());
+		let (message, data) = match metadata.get(start..end) {
+			Some(data) if !data.is_empty() => {
+				(Self { msg_type: 1, piece, total_size: Some(metadata.len()) }, data)
+			}
+			_ => (Self { msg_type: 2, piece, total_size: None }, &[][..]),
+		};
+		let mut payload = serde_bencode::to_bytes(&message).unwrap();
+		payload.extend_from_slice(data);
+		PeerMessage::Extended { id: extension_id, payload }
+	}
 }
 
 impl<'a> MetadataMessage {
@@ -32,101 +51,294 @@ impl<'a> MetadataMessage {
 	}
 }
 
+/// Length of the bencoded value at the start of `buf`, such as the header of a ut_metadata
+/// message, which a piece's data follows.
+fn bencode_len(buf: &[u8]) -> Option<usize> {
+	let (mut pos, mut depth) = (0usize, 0usize);
+	loop {
+		match *buf.get(pos)? {
+			b'i' => pos += buf[pos..].iter().position(|&b| b == b'e')? + 1,
+			b'l' | b'd' => {
+				depth += 1;
+				pos += 1;
+				continue;
+			}
+			b'e' if depth > 0 => {
+				depth -= 1;
+				pos += 1;
+			}
+			b'0'..=b'9' => {
+				let colon = pos + buf[pos..].iter().position(|&b| b == b':')?;
+				let len: usize = std::str::from_utf8(&buf[pos..colon]).ok()?.parse().ok()?;
+				pos = (colon + 1).checked_add(len).filter(|&end| end <= buf.len())?;
+			}
+			_ => return None,
+		}
+		if depth == 0 {
+			return Some(pos);
+		}
+	}
+}
+
+/// Largest info dictionary we'll fetch; a peer's claimed size is checked before we allocate.
+pub const MAX_METADATA_SIZE: usize = 8 << 20;
+
+/// Bytes in each BEP 9 metadata piece; the last one can be short.
+pub const METADATA_PIECE_LEN: usize = 16384;
+
+/// How long a peer has to answer a metadata request before another peer is asked.
+pub const METADATA_REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(20);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PieceState {
+	Missing,
+	Requested {
+		peer: SocketAddr,
+		at: tokio::time::Instant,
+	},
+	/// `peer` didn't answer in time; anyone else may be asked.
+	TimedOut {
+		peer: SocketAddr,
+	},
+	Received {
+		peer: SocketAddr,
+	},
+}
+
 struct MetaInfoInner {
 	size: usize,
 	data: Vec<u8>,
-	pieces: Vec<usize>,
-}
-
-fn num_pieces_for_size(size: usize) -> usize {
-	(size + 16383) / 16384
+	pieces: Vec<PieceState>,
+	/// Pieces of attempts that failed the hash check, with who sent them, to compare against
+	/// the real thing once we have it.
+	suspect: HashMap<(usize, SocketAddr), Vec<u8>>,
 }
 
 impl MetaInfoInner {
 	fn new(size: usize) -> Self {
-		Self { size, data: vec![0; size], pieces: vec![0; num_pieces_for_size(size)] }
+		let pieces = vec![PieceState::Missing; size.div_ceil(METADATA_PIECE_LEN)];
+		Self { size, data: vec![0; size], pieces, suspect: HashMap::new() }
 	}
-	fn num_pieces(&self) -> usize {
-		num_pieces_for_size(self.size)
+
+	fn range(&self, piece: usize) -> std::ops::Range<usize> {
+		piece * METADATA_PIECE_LEN..((piece + 1) * METADATA_PIECE_LEN).min(self.size)
+	}
+
+	/// Let other peers be asked for pieces whose requests have gone unanswered too long.
+	fn expire(&mut self) {
+		for state in &mut self.pieces {
+			if let PieceState::Requested { peer, at } = *state {
+				if at.elapsed() >= METADATA_REQUEST_TIMEOUT {
+					*state = PieceState::TimedOut { peer };
+				}
+			}
+		}
 	}
 }
 
+/// A BEP 9 info dictionary being fetched from several peers at once.
+///
+/// Each piece is tracked as missing, requested from a peer, timed out, or received from a
+/// peer. A peer that sends a piece that turns out to be wrong is banned.
 #[derive(Clone)]
 pub struct MetaInfo {
 	infohash: [u8; 20],
 	inner: Arc<Mutex<Option<MetaInfoInner>>>,
+	banned: Arc<std::sync::Mutex<HashSet<IpAddr>>>,
 }
 
 impl MetaInfo {
 	pub fn new(infohash: [u8; 20]) -> Self {
-		Self { infohash, inner: Default::default() }
+		Self { infohash, inner: Default::default(), banned: Default::default() }
 	}
 
 	pub fn infohash(&self) -> [u8; 20] {
 		self.infohash
 	}
 
-	pub async fn got_size(&self, size: usize) {
+	pub fn is_banned(&self, peer: SocketAddr) -> bool {
+		self.banned.lock().unwrap().contains(&peer.ip())
+	}
+
+	/// A peer said how big the info dictionary is, in its extension handshake.
+	pub async fn got_size(&self, peer: SocketAddr, size: usize) -> Result<(), String> {
+		if size == 0 || size > MAX_METADATA_SIZE {
+			return Err(format!("{peer} claims metadata of {size} bytes"));
+		}
 		let mut inner = self.inner.lock().await;
-		if let Some(inner) = inner.as_ref() {
-			assert_eq!(inner.size, size);
-		} else {
-			*inner = Some(MetaInfoInner::new(size));
+		match inner.as_ref() {
+			Some(inner) if inner.size != size => {
+				Err(format!("{peer} claims metadata of {size} bytes, not {}", inner.size))
+			}
+			Some(_) => Ok(()),
+			None => {
+				*inner = Some(MetaInfoInner::new(size));
+				Ok(())
+			}
 		}
 	}
 
-	pub async fn which_piece(&self) -> Option<usize> {
+	/// A piece to ask `peer` for, marking it requested. Pieces whose requests timed out go to
+	/// other peers.
+	pub async fn which_piece(&self, peer: SocketAddr) -> Option<usize> {
 		let mut guard = self.inner.lock().await;
-		let inner = guard.as_mut().unwrap();
-		let min = *inner.pieces.iter().min().unwrap();
-		if min == MAX {
-			return None;
-		}
-		let piece = inner.pieces.iter().position(|v| *v == min).unwrap();
-		inner.pieces[piece] += 1;
+		let inner = guard.as_mut()?;
+		inner.expire();
+		let piece = inner.pieces.iter().position(|state| match *state {
+			PieceState::Missing => true,
+			PieceState::TimedOut { peer: slow } => slow != peer,
+			_ => false,
+		})?;
+		inner.pieces[piece] = PieceState::Requested { peer, at: tokio::time::Instant::now() };
 		Some(piece)
 	}
 
-	pub async fn got_metadata_message(&self, data: &[u8]) -> bool {
-		let msg = MetadataMessage::from_bytes(data).unwrap();
+	/// Whether `peer` is still the one we're waiting on for `piece`: it hasn't answered, nor
+	/// timed out.
+	pub async fn waiting_on(&self, peer: SocketAddr, piece: usize) -> bool {
+		let mut guard = self.inner.lock().await;
+		let Some(inner) = guard.as_mut() else { return false };
+		inner.expire();
+		let state = inner.pieces.get(piece);
+		matches!(state, Some(PieceState::Requested { peer: asked, .. }) if *asked == peer)
+	}
+
+	/// Whether every piece has arrived, and together they match the infohash.
+	pub async fn is_complete(&self) -> bool {
+		let guard = self.inner.lock().await;
+		guard.as_ref().is_some_and(|inner| {
+			inner.pieces.iter().all(|state| matches!(state, PieceState::Received { .. }))
+		})
+	}
+
+	/// `peer` went away or refused; let others be asked for what it was asked for.
+	pub async fn release(&self, peer: SocketAddr) {
+		let mut guard = self.inner.lock().await;
+		let Some(inner) = guard.as_mut() else { return };
+		for state in &mut inner.pieces {
+			if matches!(*state, PieceState::Requested { peer: asked, .. } if asked == peer) {
+				*state = PieceState::Missing;
+			}
+		}
+	}
+
+	/// Handle a ut_metadata message from `peer`, returning whether the info dictionary is now
+	/// complete and verified. Errors are the peer's fault, and it should be dropped.
+	pub async fn got_metadata_message(
+		&self,
+		peer: SocketAddr,
+		payload: &[u8],
+	) -> Result<bool, String> {
+		let header = bencode_len(payload).ok_or("bad ut_metadata message")?;
+		let message = MetadataMessage::from_bytes(&payload[..header])
+			.map_err(|e| format!("bad ut_metadata message: {e}"))?;
 		let mut guard = self.inner.lock().await;
-		let inner = guard.as_mut().unwrap();
-		assert_eq!(msg.total_size, Some(inner.size));
-		let start = msg.piece * 16384;
-		let end = std::cmp::min(start + 16384, inner.size);
-		inner.data[start..end].copy_from_slice(&data[(data.len() - (end - start))..data.len()]);
+		let inner = guard.as_mut().ok_or("ut_metadata message before a metadata size")?;
+		let requested = matches!(
+			inner.pieces.get(message.piece),
+			Some(PieceState::Requested { peer: asked, .. } | PieceState::TimedOut { peer: asked })
+				if *asked == peer
+		);
+		match message.msg_type {
+			1 if requested => {}
+			2 if requested => {
+				inner.pieces[message.piece] = PieceState::Missing;
+				return Err(format!("rejected metadata piece {}", message.piece));
+			}
+			_ => return Err(format!("unexpected ut_metadata message {message:?}")),
+		}
+		if message.total_size != Some(inner.size) {
+			return Err(format!("total_size {:?}, not {}", message.total_size, inner.size));
+		}
+		let (range, data) = (inner.range(message.piece), &payload[header..]);
+		if data.len() != range.len() {
+			return Err(format!(
+				"metadata piece {} is {} bytes, not {}",
+				message.piece,
+				data.len(),
+				range.len()
+			));
+		}
+		inner.data[range].copy_from_slice(data);
+		inner.pieces[message.piece] = PieceState::Received { peer };
+
+		if !inner.pieces.iter().all(|state| matches!(state, PieceState::Received { .. })) {
+			return Ok(false);
+		}
 		if self.verify(&inner.data) {
-			let dict = serde_bencode::de::from_bytes::<InfoDict>(&inner.data).unwrap();
-			dbg!(&dict.name);
-			dbg!(dict.piece_length);
-			dbg!(&dict.length);
-			dbg!(&dict.files);
-
-			let files = dict.files.map(|f| serde_json::to_string(&f).unwrap());
-
-			// upsert_async!(
-			// 	Infohash {
-			// 		on self.infohash,
-			// 		dict.name,
-			// 		files
-			// 	}
-			// )
-			// .unwrap();
+			self.blame(inner);
+			return Ok(true);
+		}
 
+		// Some piece is wrong. With one sender we know who; otherwise keep what everyone sent,
+		// and see who differs from the real thing once we have it.
+		let senders: HashSet<_> = inner
+			.pieces
+			.iter()
+			.filter_map(|state| match state {
+				PieceState::Received { peer } => Some(*peer),
+				_ => None,
+			})
+			.collect();
+		for piece in 0..inner.pieces.len() {
+			if let PieceState::Received { peer } = inner.pieces[piece] {
+				let data = inner.data[inner.range(piece)].to_vec();
+				inner.suspect.insert((piece, peer), data);
+			}
+			inner.pieces[piece] = PieceState::Missing;
+		}
+		if let [liar] = senders.into_iter().collect::<Vec<_>>()[..] {
+			self.ban(liar);
+		}
+		warn!("metadata for {} failed its hash check", hex::encode(self.infohash));
+		Ok(false)
+	}
+
+	/// Ban the senders of pieces from failed attempts that differ from the verified data.
+	fn blame(&self, inner: &MetaInfoInner) {
+		for (&(piece, peer), data) in &inner.suspect {
+			if inner.data[inner.range(piece)] != data[..] {
+				self.ban(peer);
+			}
+		}
+	}
+
+	fn ban(&self, peer: SocketAddr) {
+		info!("banning {peer} for sending bad metadata for {}", hex::encode(self.infohash));
+		self.banned.lock().unwrap().insert(peer.ip());
+	}
+
+	/// The info dictionary, once all of it has arrived and matches the infohash.
+	pub async fn data(&self) -> Option<Vec<u8>> {
+		let guard = self.inner.lock().await;
+		let inner = guard.as_ref()?;
+		self.verify(&inner.data).then(|| inner.data.clone())
+	}
+
+	/// Record the name, length and files of the torrent in the infohash table.
+	#[tracked::tracked]
+	pub async fn save(&self) -> Result<(), tracked::StringError> {
+		let Some(data) = self.data().await else { return Ok(()) };
+		let infohash = self.infohash;
+		let dict = InfoDict::from_bytes(&data)
+			.map_err(|e| format!("metadata for {} is not an info dictionary: {e}", hex::encode(infohash)))?;
+		debug!("saving metadata for {}: {:?}, {:?} bytes", hex::encode(infohash), dict.name, dict.length);
+
+		let files = dict.files.map(|f| serde_json::to_string(&f)).transpose()?;
+		let (name, length) = (dict.name, dict.length);
+		tokio::task::spawn_blocking(move || -> Result<(), tracked::StringError> {
 			execute!(
 				"INSERT INTO infohash(infohash, name, length, files)"
-				"VALUES (" self.infohash, dict.name, dict.length, files ")"
+				"VALUES (" infohash, name, length, files ")"
 				"ON CONFLICT(infohash) DO UPDATE SET"
-					"name = " dict.name,
-					"length = " dict.length,
+					"name = " name,
+					"length = " length,
 					"files = " files
-			)
-			.unwrap();
-
-			true
-		} else {
-			false
-		}
+			)?;
+			Ok(())
+		})
+		.await??;
+		Ok(())
 	}
 
 	fn verify(&self, data: &[u8]) -> bool {
@@ -140,19 +352,72 @@ impl MetaInfo {
 	fn subscribe() {}
 }
 
+/// Largest piece we'll handle; a whole piece is held in memory while it's checked.
+pub const MAX_PIECE_LENGTH: usize = 64 << 20;
+
 #[derive(Debug, Deserialize)]
 pub struct InfoDict {
-	files: Option<Vec<File>>,
-	length: Option<u64>,
-	name: String,
+	pub files: Option<Vec<File>>,
+	pub length: Option<u64>,
+	pub name: String,
 	#[serde(rename = "piece length")]
-	piece_length: usize,
+	pub piece_length: usize,
+	/// SHA-1 hashes of the pieces, 20 bytes each.
 	#[serde(with = "serde_bytes")]
-	pieces: Vec<u8>,
+	pub pieces: Vec<u8>,
+}
+
+impl InfoDict {
+	pub fn from_bytes(buf: &[u8]) -> Result<Self, serde_bencode::Error> {
+		serde_bencode::de::from_bytes::<Self>(buf)
+	}
+
+	/// Bytes in the torrent, across all its files; [`InfoDict::validate`] checks this fits.
+	pub fn total_length(&self) -> u64 {
+		self.checked_total_length().unwrap_or(u64::MAX)
+	}
+
+	fn checked_total_length(&self) -> Option<u64> {
+		match &self.files {
+			Some(files) => files.iter().try_fold(0u64, |total, file| total.checked_add(file.length)),
+			None => Some(self.length.unwrap_or_default()),
+		}
+	}
+
+	pub fn num_pieces(&self) -> usize {
+		self.pieces.len() / 20
+	}
+
+	pub fn piece_hash(&self, piece: usize) -> [u8; 20] {
+		self.pieces[piece * 20..piece * 20 + 20].try_into().unwrap()
+	}
+
+	/// Where `piece` starts in the torrent's bytes.
+	pub fn piece_offset(&self, piece: usize) -> u64 {
+		piece as u64 * self.piece_length as u64
+	}
+
+	/// Length of `piece`; only the last one can be short.
+	pub fn piece_len(&self, piece: usize) -> usize {
+		(self.total_length() - self.piece_offset(piece)).min(self.piece_length as u64) as usize
+	}
+
+	/// Check that the piece hashes cover the files exactly, and pieces fit in memory.
+	pub fn validate(&self) -> Result<(), String> {
+		if !(1..=MAX_PIECE_LENGTH).contains(&self.piece_length) || !self.pieces.len().is_multiple_of(20) {
+			return Err(format!("bad piece length {} or hashes", self.piece_length));
+		}
+		let total_length = self.checked_total_length().ok_or("file lengths overflow")?;
+		let expected = total_length.div_ceil(self.piece_length as u64);
+		if self.num_pieces() as u64 != expected {
+			return Err(format!("{} piece hashes for {expected} pieces", self.num_pieces()));
+		}
+		Ok(())
+	}
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct File {
-	length: u64,
-	path: Vec<String>,
+	pub length: u64,
+	pub path: Vec<String>,
 }
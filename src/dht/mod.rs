@@ -2,13 +2,23 @@
 
 turbomod::dir!(use "src/dht");
 
+pub use bootstrap::{BootstrapStatus, DEFAULT_ROUTERS};
+pub use bt_structs::{Handshake, PeerMessage};
+pub use choke::{ChokeSlot, Choker, UPLOAD_SLOTS};
+pub use download::{PiecePicker, BLOCK_SIZE, DEFAULT_PRIORITY};
+pub use lookup::{LookupKind, LookupNode};
+pub use metainfo::{InfoDict, MetaInfo, MAX_METADATA_SIZE, MAX_PIECE_LENGTH, METADATA_PIECE_LEN};
+pub use peer::{Bitfield, PeerState};
+pub use rate_limit::{RateLimiter, RateLimits};
+pub use routing_table::NodeIdPolicy;
+pub use simnet::{SimConfig, SimHost, SimNetwork};
+pub use transport::{DatagramSocket, PeerListener, PeerStream, TokioTransport, Transport};
+
+use futures::StreamExt;
 use log::*;
 use once_cell::sync::{Lazy, OnceCell};
 use std::collections::{HashMap, HashSet};
-use tokio::{
-	io::{AsyncReadExt, AsyncWriteExt},
-	net::{TcpSocket, UdpSocket},
-};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use turbosql::*;
 
 #[derive(Debug)]
@@ -17,7 +27,7 @@ pub enum Progress<T> {
 	Complete { result: T, status: String },
 }
 
-type ProgressStream<T> =
+pub type ProgressStream<T> =
 	std::pin::Pin<Box<dyn futures::Stream<Item = Result<Progress<T>, tracked::StringError>> + Send>>;
 
 macro_rules! progress {
@@ -50,17 +60,22 @@ macro_rules! err {
 	}};
 }
 
-macro_rules! self_id {
-	() => {{
-		*SELF_ID.get().unwrap()
-	}};
-}
-
 #[derive(Turbosql, Default)]
 struct SelfId {
 	rowid: Option<i64>,
 	ip: Option<String>,
 	id: Option<[u8; 20]>,
+	/// Set for crawling identities, which are keyed by slot rather than IP.
+	slot: Option<i64>,
+	/// When we last switched to this id; we start up with the most recent one.
+	last_used_ms: Option<i64>,
+}
+
+/// Our ed25519 key for publishing BEP 46 mutable torrents.
+#[derive(Turbosql, Default)]
+struct PublisherKey {
+	rowid: Option<i64>,
+	secret: Option<[u8; 32]>,
 }
 
 #[derive(Turbosql, Default)]
@@ -68,8 +83,27 @@ pub struct Node {
 	pub rowid: Option<i64>,
 	pub host: Option<String>,
 	pub id: Option<[u8; 20]>,
-	// last_ping_attempt_ms: Option<i64>,
+	pub last_ping_attempt_ms: Option<i64>,
+	pub last_ping_response_ms: Option<i64>,
 	pub last_response_ms: Option<i64>,
+	/// Whether the node's id matches its IP per BEP 42.
+	pub bep42: Option<bool>,
+	/// Size of the node's BEP 51 sample store, from the `num` it last reported.
+	pub num_samples: Option<i64>,
+	/// Smoothed round-trip time of our queries.
+	pub rtt_ms: Option<i64>,
+	/// Consecutive queries the node didn't answer.
+	pub failures: Option<i64>,
+}
+
+/// A BEP 33 estimate of a swarm's size at one point in time.
+#[derive(Turbosql, Default)]
+pub struct Scrape {
+	pub rowid: Option<i64>,
+	pub infohash: Option<[u8; 20]>,
+	pub seeders: Option<i64>,
+	pub leechers: Option<i64>,
+	pub scraped_ms: Option<i64>,
 }
 
 #[derive(Turbosql, Default)]
@@ -80,137 +114,448 @@ pub struct Infohash {
 	pub name: Option<String>,
 	pub length: Option<i64>,
 	pub files: Option<String>,
+	/// Node id of the identity that first sampled this infohash.
+	pub discovered_by: Option<[u8; 20]>,
 }
 
-static BROADCAST: Lazy<tokio::sync::broadcast::Sender<(String, ResponseArgs)>> =
-	Lazy::new(|| tokio::sync::broadcast::channel(200).0);
-static SOCK: OnceCell<tokio::net::UdpSocket> = OnceCell::new();
-static INTERFACE: OnceCell<Option<String>> = OnceCell::new();
-static SELF_ID: OnceCell<[u8; 20]> = OnceCell::new();
-
-#[tracked::tracked]
-pub async fn launch_dht(interface: Option<String>, port: u16) -> Result<(), tracked::StringError> {
-	use std::net::{SocketAddr, ToSocketAddrs};
-	INTERFACE.set(interface).map_err(|_| "SOCK already set")?;
-
-	let mut addrs_iter = "api64.ipify.org:80".to_socket_addrs().unwrap();
-	let socket = TcpSocket::new_v4()?;
-	#[cfg(all(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
-	if let Some(Some(interface)) = INTERFACE.get() {
-		socket.bind_device(Some(interface.as_bytes())).unwrap();
-	}
-	#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
-	if INTERFACE.get().unwrap().is_some() {
-		error!("--interface only supported on Linux!");
-		std::process::exit(1);
-	}
-	let mut stream = socket.connect(addrs_iter.next().unwrap()).await?;
-	stream.write_all("GET / HTTP/1.0\r\nHost: api64.ipify.org\r\n\r\n".as_bytes()).await.unwrap();
-	let mut buffer = Vec::new();
-	stream.read_to_end(&mut buffer).await?;
-	let ip = String::from_utf8_lossy(&buffer).split('\n').last().unwrap().to_string();
-	info!("external ip is {:?}", ip);
-
-	SELF_ID
-		.set(match select!(Option<SelfId> "WHERE ip = " ip)? {
-			Some(SelfId { id, .. }) => id.unwrap(),
-			None => {
-				let id = id_from_ip(&ip.parse().unwrap());
-				SelfId { rowid: None, ip: Some(ip), id: Some(id) }.insert()?;
-				id
-			}
-		})
-		.map_err(|_| "SELF_ID already set")?;
+/// Infohashes we announce, with the port and `implied_port` flag to announce them with, and
+/// whether we have all of the torrent (BEP 33).
+type Seeding = HashMap<[u8; 20], (u16, bool, bool)>;
+
+/// Tasks that keep our published torrents alive, by public key and salt.
+type Republishing = HashMap<([u8; 32], Vec<u8>), tokio::task::AbortHandle>;
+
+/// How often we re-announce the torrents we're seeding.
+const REANNOUNCE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(15 * 60);
+
+/// How often we refresh the routing tables.
+const ROUTING_TABLE_MAINTENANCE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// How often we check on the nodes in the node table.
+const NODE_MAINTENANCE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+/// Nodes in the node table we haven't heard from for this long get pinged.
+const NODE_STALE_AFTER: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// Consecutive unanswered pings after which a node is deleted from the node table.
+const NODE_MAX_FAILURES: i64 = 3;
 
-	let udp_socket = UdpSocket::bind(format!("0.0.0.0:{port}")).await.unwrap();
+/// Most `sample_infohashes` queries the crawler has outstanding.
+const CRAWL_IN_FLIGHT: usize = 64;
+
+/// How to start a [`Dht`]; get one from [`Dht::builder`].
+pub struct DhtBuilder {
+	transport: Option<std::sync::Arc<dyn Transport>>,
+	interface: Option<String>,
+	port: u16,
+	external_ip: Option<std::net::IpAddr>,
+	node_id_policy: NodeIdPolicy,
+	read_only: bool,
+	routers: Vec<String>,
+	rate_limits: RateLimits,
+	persistent: bool,
+	maintain_node_table: bool,
+	identity: Option<(usize, usize)>,
+}
+
+impl Default for DhtBuilder {
+	fn default() -> Self {
+		Self {
+			transport: None,
+			interface: None,
+			port: 0,
+			external_ip: None,
+			node_id_policy: NodeIdPolicy::default(),
+			read_only: false,
+			routers: DEFAULT_ROUTERS.iter().map(|router| router.to_string()).collect(),
+			rate_limits: RateLimits::default(),
+			persistent: true,
+			maintain_node_table: true,
+			identity: None,
+		}
+	}
+}
 
-	#[cfg(all(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
-	if let Some(Some(interface)) = INTERFACE.get() {
-		udp_socket.bind_device(Some(interface.as_bytes())).unwrap();
+impl DhtBuilder {
+	/// Network to run on instead of the host's, e.g. a [`SimNetwork`] in tests.
+	pub fn transport(mut self, transport: std::sync::Arc<dyn Transport>) -> Self {
+		self.transport = Some(transport);
+		self
 	}
 
-	SOCK.set(udp_socket).map_err(|_| "SOCK already set")?;
+	/// Network interface to bind our sockets to, when using the host's network; only
+	/// supported on Linux.
+	pub fn interface(mut self, interface: Option<String>) -> Self {
+		self.interface = interface;
+		self
+	}
 
-	// let sock = std::sync::Arc::new();
-	// let sock_clone = sock.clone();
+	/// UDP port for both address families. The default, 0, picks a free one.
+	pub fn port(mut self, port: u16) -> Self {
+		self.port = port;
+		self
+	}
 
-	// let mut target = [0u8; 20];
-	// rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut target);
+	/// External IP to derive our node id from, instead of voting on what other nodes report.
+	pub fn external_ip(mut self, external_ip: Option<std::net::IpAddr>) -> Self {
+		self.external_ip = external_ip;
+		self
+	}
 
-	// tokio::spawn(async move {
-	// 	for node in select!(Vec<Node>).unwrap().into_iter() {
-	// 		let host = node.host.as_ref().unwrap();
+	/// How to treat nodes whose ids don't match their IP (BEP 42).
+	pub fn node_id_policy(mut self, node_id_policy: NodeIdPolicy) -> Self {
+		self.node_id_policy = node_id_policy;
+		self
+	}
 
-	// 		SOCK.get()
-	// 			.unwrap()
-	// 			.send_to(
-	// 				&SampleInfohashesQuery { id: *SELF_ID.get().unwrap(), target }.into_bytes(),
-	// 				&host,
-	// 			)
-	// 			.await;
+	/// Don't answer queries, and tell other nodes so (BEP 43).
+	pub fn read_only(mut self, read_only: bool) -> Self {
+		self.read_only = read_only;
+		self
+	}
 
-	// 		tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-	// println!("{:?} bytes sent to {:?}", len, host);
+	/// Routers (host:port) to bootstrap from when we know too few nodes.
+	pub fn routers(mut self, routers: Vec<String>) -> Self {
+		self.routers = routers;
+		self
+	}
 
-	// let hostip = host.split(':').into_iter().next().unwrap();
-	// let host = format!("{}:6881", hostip);
-	// let len = sock.send_to(&PingQuery { id }.into_bytes(), &host).await?;
-	// println!("{:?} bytes sent to {:?}", len, host);
+	/// Most packets a second we send, overall and to one host or network.
+	pub fn rate_limits(mut self, rate_limits: RateLimits) -> Self {
+		self.rate_limits = rate_limits;
+		self
+	}
 
-	// let host = format!("{}:6882", hostip);
-	// let len = sock.send_to(&PingQuery { id }.into_bytes(), &host).await?;
-	// println!("{:?} bytes sent to {:?}", len, host);
+	/// Whether to load and save our node id, the node table and the infohashes we see in the
+	/// database. On by default; turn it off for throwaway nodes.
+	pub fn persistent(mut self, persistent: bool) -> Self {
+		self.persistent = persistent;
+		self
+	}
 
-	// let host = format!("{}:6883", hostip);
-	// let len = sock.send_to(&PingQuery { id }.into_bytes(), &host).await?;
-	// println!("{:?} bytes sent to {:?}", len, host);
+	/// Whether to ping stale nodes in the database's node table and prune dead ones. On by
+	/// default; turn it off for all but one of the nodes sharing a database.
+	pub fn maintain_node_table(mut self, maintain_node_table: bool) -> Self {
+		self.maintain_node_table = maintain_node_table;
+		self
+	}
 
-	// let len = sock.send_to(&FindNodeQuery { id, target }.into_bytes(), &host).await?;
-	// println!("{:?} bytes sent to {:?}", len, host);
-	// 	}
-	// });
+	/// Run as crawling identity `slot` of `count`: our node id stays in that slice of the keyspace
+	/// whatever our IP, and [`Dht::crawl`] explores it. [`DhtBuilder::build`] fails unless
+	/// `slot < count`.
+	pub fn identity(mut self, slot: usize, count: usize) -> Self {
+		self.identity = Some((slot, count));
+		self
+	}
 
-	tokio::spawn(async move {
-		let mut buf = [0; 1500];
-		loop {
-			let (len, addr) = SOCK.get().unwrap().recv_from(&mut buf).await.unwrap_or_else(|e| {
-				error!("recv_from failed: {:?}", e);
-				std::process::exit(1);
-			});
+	/// Bind the sockets, start answering queries, and bootstrap any routing table that has
+	/// too few nodes.
+	#[tracked::tracked]
+	pub async fn build(self) -> Result<Dht, tracked::StringError> {
+		use std::net::SocketAddr;
+		let DhtBuilder {
+			transport,
+			interface,
+			port,
+			external_ip,
+			node_id_policy,
+			read_only,
+			routers,
+			rate_limits,
+			persistent,
+			maintain_node_table,
+			identity,
+		} = self;
+
+		rate_limits.validate()?;
+		if let Some((slot, count)) = identity.filter(|&(slot, count)| slot >= count) {
+			return Err(format!("no identity slot {slot} of {count}").into());
+		}
 
-			// println!(
-			// 	"{:?} bytes received from {:?}: {:?}",
-			// 	len,
-			// 	addr,
-			// 	String::from_utf8_lossy(&buf[..len])
-			// );
-
-			// tokio::task::spawn_blocking(move || {
-			// 	execute!(
-			// 		"UPDATE node"
-			// 		"SET last_response_ms = " now_ms()
-			// 		"WHERE host = " addr.to_string()
-			// 	)
-			// 	.unwrap();
-			// });
-
-			if let Ok(response) = Response::from_bytes(&buf[..len]) {
-				tokio::task::spawn_blocking(move || {
-					if let Err(e) = process_response(addr.to_string(), response) {
-						warn!("process_response error: {:?}", e);
-					}
+		let transport = match transport {
+			Some(transport) => transport,
+			None => std::sync::Arc::new(TokioTransport::new(interface)?),
+		};
+
+		// Until other nodes tell us our IP, reuse the id we had last time.
+		let (ip, id) = match (external_ip, identity) {
+			(ip, Some((slot, count))) if persistent => (ip, self_id_for_slot(slot, count)?),
+			(ip, Some((slot, count))) => (ip, id_in_slice(slot, count)),
+			(Some(ip), None) if persistent => (Some(ip), self_id_for_ip(ip)?),
+			(Some(ip), None) => (Some(ip), id_from_ip(&ip)),
+			(None, None) if persistent => match select!(Option<SelfId>
+				"WHERE slot IS NULL ORDER BY last_used_ms DESC, rowid DESC LIMIT 1"
+			)? {
+				Some(SelfId { ip, id: Some(id), .. }) => (ip.and_then(|ip| ip.parse().ok()), id),
+				_ => (None, rand::random()),
+			},
+			(None, None) => (None, rand::random()),
+		};
+		info!("external ip is {:?}", ip);
+
+		let mut table = RoutingTable::new(id, node_id_policy);
+		let mut table6 = RoutingTable::new(id, node_id_policy);
+		if persistent {
+			for node in select!(Vec<Node> "WHERE id IS NOT NULL ORDER BY last_response_ms DESC")? {
+				let (Some(host), Some(id)) = (node.host, node.id) else { continue };
+				let Ok(addr) = host.parse::<SocketAddr>() else { continue };
+				let last_response = node.last_response_ms.and_then(|ms| {
+					let age = std::time::Duration::from_millis((now_ms() - ms).max(0) as u64);
+					std::time::Instant::now().checked_sub(age)
 				});
+				if addr.is_ipv6() { &mut table6 } else { &mut table }.load(id, addr, last_response);
 			}
+			info!("loaded {} IPv4 and {} IPv6 nodes into routing tables", table.len(), table6.len());
 		}
-	});
 
+		let sock = transport.bind_udp(false, port).await?;
+
+		// With port 0, bind IPv6 to whatever port IPv4 got, so we have one port to announce.
+		let sock6 = match transport.bind_udp(true, sock.local_addr()?.port()).await {
+			Ok(sock6) => Some(sock6),
+			Err(e) => {
+				warn!("IPv6 DHT disabled, could not bind: {:?}", e);
+				None
+			}
+		};
+
+		let dht = Dht(std::sync::Arc::new(DhtState {
+			transport,
+			sock,
+			sock6,
+			self_id: std::sync::RwLock::new(id),
+			external_ip: std::sync::Mutex::new(ip),
+			external_ip_override: external_ip,
+			ip_voter: Default::default(),
+			routing_table: std::sync::Mutex::new(table),
+			routing_table6: std::sync::Mutex::new(table6),
+			transactions: Default::default(),
+			tokens: Default::default(),
+			peer_store: Default::default(),
+			items: Default::default(),
+			seeding: Default::default(),
+			torrents: Default::default(),
+			file_priorities: Default::default(),
+			republishing: Default::default(),
+			read_only,
+			persistent,
+			identity,
+			rate_limiter: std::sync::Mutex::new(RateLimiter::new(rate_limits)),
+			routers,
+			bootstrap: Default::default(),
+			new_infohashes: Default::default(),
+			tasks: Default::default(),
+		}));
+
+		for ipv6 in dht.families() {
+			let sock = if ipv6 { dht.0.sock6.clone().unwrap() } else { dht.0.sock.clone() };
+			let task = tokio::spawn(recv_loop(std::sync::Arc::downgrade(&dht.0), sock));
+			dht.0.tasks.lock().unwrap().push(task.abort_handle());
+		}
+
+		// Peers connect to our DHT port over TCP for the torrents we have, over each family we
+		// announce on.
+		for ipv6 in dht.families() {
+			match dht.0.transport.listen(ipv6, dht.0.sock.local_addr()?.port()).await {
+				Ok(listener) => {
+					let task = tokio::spawn(accept_loop(std::sync::Arc::downgrade(&dht.0), listener));
+					dht.0.tasks.lock().unwrap().push(task.abort_handle());
+				}
+				Err(e) => warn!("not accepting IPv{} peer connections: {:?}", if ipv6 { 6 } else { 4 }, e),
+			}
+		}
+
+		// Lookups go nowhere without nodes, so wait for bootstrap before anyone starts one.
+		let empty =
+			dht.families().into_iter().filter(|&ipv6| dht.routing_table(ipv6).len() < BOOTSTRAP_BELOW);
+		futures::future::join_all(empty.map(|ipv6| dht.bootstrap(ipv6))).await;
+
+		dht.every(ROUTING_TABLE_MAINTENANCE_INTERVAL, |dht| async move {
+			dht.maintain_routing_table().await;
+		});
+
+		if persistent && maintain_node_table {
+			dht.every(NODE_MAINTENANCE_INTERVAL, |dht| async move {
+				if let Err(e) = dht.maintain_node_table().await {
+					warn!("maintain_node_table error: {:?}", e);
+				}
+			});
+		}
+
+		dht.every(CHOKE_INTERVAL, |dht| async move {
+			let torrents: Vec<_> = dht.0.torrents.lock().unwrap().values().cloned().collect();
+			for torrent in torrents {
+				let seeding = torrent.picker.lock().unwrap().is_complete();
+				torrent.choker.lock().unwrap().rechoke(seeding);
+			}
+		});
+
+		dht.every(REANNOUNCE_INTERVAL, |dht| async move {
+			let seeding = dht.0.seeding.lock().unwrap().clone();
+			for (info_hash, (port, implied_port, seed)) in seeding {
+				let dht = dht.clone();
+				tokio::spawn(async move { dht.announce_peer(info_hash, port, implied_port, seed).await });
+			}
+		});
+
+		Ok(dht)
+	}
+}
+
+/// A DHT node, with its own sockets, node id, routing tables and storage.
+///
+/// Clones share the node. Its background tasks stop when the last clone is dropped.
+#[derive(Clone)]
+pub struct Dht(std::sync::Arc<DhtState>);
+
+struct DhtState {
+	transport: std::sync::Arc<dyn Transport>,
+	sock: std::sync::Arc<dyn DatagramSocket>,
+	sock6: Option<std::sync::Arc<dyn DatagramSocket>>,
+	self_id: std::sync::RwLock<[u8; 20]>,
+	external_ip: std::sync::Mutex<Option<std::net::IpAddr>>,
+	/// Set when we were given our external IP; votes are then ignored.
+	external_ip_override: Option<std::net::IpAddr>,
+	ip_voter: std::sync::Mutex<IpVoter>,
+	routing_table: std::sync::Mutex<RoutingTable>,
+	routing_table6: std::sync::Mutex<RoutingTable>,
+	transactions: std::sync::Mutex<Transactions>,
+	tokens: std::sync::Mutex<TokenSecrets>,
+	peer_store: std::sync::Mutex<PeerStore>,
+	items: std::sync::Mutex<ItemStore>,
+	seeding: std::sync::Mutex<Seeding>,
+	/// Torrents we serve to peers that connect to us, by infohash.
+	torrents: std::sync::Mutex<HashMap<[u8; 20], std::sync::Arc<Download>>>,
+	/// File priorities by infohash, applied when a torrent's download starts.
+	file_priorities: std::sync::Mutex<HashMap<[u8; 20], Vec<u8>>>,
+	republishing: std::sync::Mutex<Republishing>,
+	/// BEP 43: we mark our queries `ro` and don't answer any.
+	read_only: bool,
+	persistent: bool,
+	/// Crawling identity slot and count; our node id stays in that slice of the keyspace.
+	identity: Option<(usize, usize)>,
+	rate_limiter: std::sync::Mutex<RateLimiter>,
+	routers: Vec<String>,
+	bootstrap: std::sync::Mutex<[BootstrapStatus; 2]>,
+	/// Infohashes from `sample_infohashes` responses that weren't in the database yet.
+	new_infohashes: std::sync::atomic::AtomicU64,
+	/// Background tasks to abort when the node goes away.
+	tasks: std::sync::Mutex<Vec<tokio::task::AbortHandle>>,
+}
+
+impl Drop for DhtState {
+	fn drop(&mut self) {
+		for task in self.tasks.get_mut().unwrap().drain(..) {
+			task.abort();
+		}
+	}
+}
+
+/// Our persisted node id for `ip`, generating a BEP 42 compliant one if we've never had that IP.
+fn self_id_for_ip(ip: std::net::IpAddr) -> Result<[u8; 20], turbosql::Error> {
+	let (ip_string, now) = (ip.to_string(), now_ms());
+	Ok(match select!(Option<SelfId> "WHERE ip = " ip_string)? {
+		Some(SelfId { rowid: Some(rowid), id: Some(id), .. }) => {
+			execute!("UPDATE selfid SET last_used_ms = " now " WHERE rowid = " rowid)?;
+			id
+		}
+		_ => {
+			let id = id_from_ip(&ip);
+			SelfId { ip: Some(ip_string), id: Some(id), last_used_ms: Some(now), ..Default::default() }
+				.insert()?;
+			id
+		}
+	})
+}
+
+/// Our persisted node id for crawling identity `slot` of `count`, generating a new one if we've
+/// never had that slot or its id is in another slice, as when `count` changes.
+fn self_id_for_slot(slot: usize, count: usize) -> Result<[u8; 20], turbosql::Error> {
+	let slot_i64 = slot as i64;
+	Ok(match select!(Option<SelfId> "WHERE slot = " slot_i64)? {
+		Some(SelfId { id: Some(id), .. }) if keyspace_slice(&id, count) == slot => id,
+		existing => {
+			let id = id_in_slice(slot, count);
+			match existing.and_then(|existing| existing.rowid) {
+				Some(rowid) => {
+					execute!("UPDATE selfid SET id = " id " WHERE rowid = " rowid)?;
+				}
+				None => {
+					SelfId { id: Some(id), slot: Some(slot_i64), ..Default::default() }.insert()?;
+				}
+			}
+			id
+		}
+	})
+}
+
+/// Our persisted publishing key, generating one the first time.
+pub fn publisher_key() -> Result<ed25519_dalek::SigningKey, turbosql::Error> {
+	Ok(match select!(Option<PublisherKey> "ORDER BY rowid LIMIT 1")? {
+		Some(PublisherKey { secret: Some(secret), .. }) => ed25519_dalek::SigningKey::from_bytes(&secret),
+		_ => {
+			let key = ed25519_dalek::SigningKey::generate(&mut rand::thread_rng());
+			PublisherKey { rowid: None, secret: Some(key.to_bytes()) }.insert()?;
+			key
+		}
+	})
+}
+
+/// Read packets from `sock` and hand them to the node, until the node is dropped.
+async fn recv_loop(dht: std::sync::Weak<DhtState>, sock: std::sync::Arc<dyn DatagramSocket>) {
+	let mut buf = [0; 1500];
+	loop {
+		let (len, addr) = match sock.recv_from(&mut buf).await {
+			Ok(received) => received,
+			Err(e) => return error!("recv_from failed: {:?}", e),
+		};
+		let Some(dht) = dht.upgrade() else { return };
+		Dht(dht).handle_packet(addr, &buf[..len]).await;
+	}
+}
+
+async fn accept_loop(dht: std::sync::Weak<DhtState>, listener: Box<dyn PeerListener>) {
+	loop {
+		let (stream, addr) = match listener.accept().await {
+			Ok(accepted) => accepted,
+			Err(e) => return error!("accept failed: {:?}", e),
+		};
+		let Some(dht) = dht.upgrade() else { return };
+		tokio::spawn(async move { Dht(dht).accept_peer(stream, addr).await });
+	}
+}
+
+fn save_routing_table(nodes: Vec<RoutingNode>) -> Result<(), Box<dyn std::error::Error>> {
+	let now = std::time::Instant::now();
+	execute!("BEGIN TRANSACTION")?;
+	for node in nodes {
+		let host = node.addr.to_string();
+		let last_response_ms =
+			node.last_response.map(|t| now_ms() - now.duration_since(t).as_millis() as i64);
+		let rtt_ms = node.rtt.map(|rtt| rtt.as_millis() as i64);
+		execute!(
+			"INSERT INTO node(host, id, last_response_ms, bep42, rtt_ms, failures)"
+			"VALUES (" host, node.id, last_response_ms, node.compliant, rtt_ms, node.failures ")"
+			"ON CONFLICT(host) DO UPDATE SET"
+				"id = " node.id,
+				"last_response_ms = " last_response_ms,
+				"bep42 = " node.compliant,
+				"rtt_ms = COALESCE(" rtt_ms ", rtt_ms),"
+				"failures = " node.failures
+		)?;
+	}
+	execute!("COMMIT")?;
 	Ok(())
 }
 
+/// Store the infohash samples in a `sample_infohashes` response, returning how many were new.
+/// New ones are credited to `discovered_by`, the node id we asked with.
 fn process_response(
 	addr: String,
 	response: ResponseArgs,
-) -> Result<(), Box<dyn std::error::Error>> {
+	discovered_by: [u8; 20],
+) -> Result<u64, Box<dyn std::error::Error>> {
+	let mut new = 0;
 	if let ResponseArgs { num, interval, samples: Some(Bytes::Bytes(ref samples)), .. } = response {
 		// println!(
 		// 	"got {} bytes of samples, total {:?}, interval {:?} from {:?}",
@@ -222,11 +567,22 @@ fn process_response(
 
 		execute!("BEGIN TRANSACTION")?;
 		for infohash in samples.chunks_exact(20) {
-			execute!("INSERT OR IGNORE INTO infohash(infohash) VALUES (" infohash ")")?;
+			new += execute!(
+				"INSERT OR IGNORE INTO infohash(infohash, discovered_by)"
+				"VALUES (" infohash, discovered_by ")"
+			)? as u64;
+		}
+		if let (Some(num), 20) = (num, response.id.len()) {
+			let id = response.id();
+			execute!(
+				"INSERT INTO node(host, id, num_samples)"
+				"VALUES (" addr, id, num ")"
+				"ON CONFLICT(host) DO UPDATE SET"
+					"id = " id,
+					"num_samples = " num
+			)?;
 		}
 		execute!("COMMIT")?;
-
-		return Ok(());
 	}
 
 	// for node in response.nodes() {
@@ -237,218 +593,1590 @@ fn process_response(
 	// 	)?;
 	// }
 
-	let _ = BROADCAST.send((addr, response));
+	Ok(new)
+}
 
-	Ok(())
+#[derive(Debug)]
+pub enum RequestError {
+	/// The node didn't answer within [`QUERY_TIMEOUT`].
+	Timeout,
+	Send(std::io::Error),
+	/// The node answered with a KRPC error.
+	Krpc(ErrorReply),
+	/// Every transaction id is taken by a query still in flight.
+	NoTransactionId,
+}
+
+/// Forgets a query whose request is dropped before it finishes, as when a lookup that's done
+/// drops the queries it still has in flight.
+struct Pending<'a> {
+	transactions: &'a std::sync::Mutex<Transactions>,
+	t: [u8; 2],
+	sent: bool,
+	finished: bool,
+}
+
+impl Drop for Pending<'_> {
+	fn drop(&mut self) {
+		if self.finished {
+			return;
+		}
+		let mut transactions = self.transactions.lock().unwrap();
+		if self.sent {
+			transactions.time_out(self.t);
+		} else {
+			transactions.cancel(self.t);
+		}
+	}
+}
+
+/// What [`Dht::get_peers`] found.
+#[derive(Debug)]
+pub struct GetPeersResult {
+	/// The K closest nodes to the infohash that answered.
+	pub closest: Vec<LookupNode>,
+	/// The info dictionary, if a peer sent all of it.
+	pub metadata: Option<Vec<u8>>,
+	/// Peers the lookup returned for the infohash.
+	pub peers: Vec<std::net::SocketAddr>,
+}
+
+type PeerReader = tokio::io::BufReader<tokio::io::ReadHalf<Box<dyn PeerStream>>>;
+type PeerWriter = tokio::io::WriteHalf<Box<dyn PeerStream>>;
+
+/// Block requests we keep outstanding with each peer we download from.
+const REQUEST_PIPELINE: usize = 8;
+
+/// Block requests a peer may have queued with us; one that asks for more is dropped.
+const MAX_QUEUED_UPLOADS: usize = 256;
+
+/// How long a download with no live peers waits before looking for more.
+const PEER_LOOKUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// A peer we download from is dropped after this long without a message.
+const PEER_IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
+
+#[derive(Debug)]
+pub enum LookupEvent {
+	/// A node we queried answered.
+	Response { addr: std::net::SocketAddr, response: Box<ResponseArgs> },
+	/// The closest set stopped improving; these are the K closest nodes that answered.
+	Complete { closest: Vec<LookupNode> },
+}
+
+fn lookup_query(kind: LookupKind, id: [u8; 20], target: [u8; 20], t: &[u8], ro: bool) -> Vec<u8> {
+	match kind {
+		LookupKind::FindNode => FindNodeQuery { id, target }.into_bytes(t, ro),
+		LookupKind::GetPeers => GetPeersQuery { id, info_hash: target, scrape: None }.into_bytes(t, ro),
+		LookupKind::Scrape => GetPeersQuery { id, info_hash: target, scrape: Some(1) }.into_bytes(t, ro),
+		LookupKind::SampleInfohashes => SampleInfohashesQuery { id, target }.into_bytes(t, ro),
+		LookupKind::Get => GetQuery { id, target, seq: None }.into_bytes(t, ro),
+	}
 }
 
-#[tracked::tracked]
-pub fn get_peers(infohash: impl Into<String>) -> ProgressStream<String> {
-	let infohash = infohash.into();
-	Box::pin(async_stream::try_stream! {
-		// let mut target = [0u8; 20];
+impl Dht {
+	pub fn builder() -> DhtBuilder {
+		DhtBuilder::default()
+	}
 
-		let mut packets_sent = 0;
-		let mut packets_recv = 0;
-		let mut our_hosts = HashSet::new();
-		let mut peers = HashMap::new();
+	pub fn self_id(&self) -> [u8; 20] {
+		*self.0.self_id.read().unwrap()
+	}
 
-		let info_hash: [u8; 20] =
-			hex::decode(&infohash)?.try_into().map_err(|_| "infohash not 20 hex bytes")?;
+	/// The address our IPv4 or IPv6 socket is bound to, if we have one.
+	pub fn local_addr(&self, ipv6: bool) -> Option<std::net::SocketAddr> {
+		self.sock(ipv6).ok()?.local_addr().ok()
+	}
 
-		let metainfo = MetaInfo::new(info_hash);
+	fn routing_table(&self, ipv6: bool) -> std::sync::MutexGuard<'_, RoutingTable> {
+		if ipv6 { &self.0.routing_table6 } else { &self.0.routing_table }.lock().unwrap()
+	}
 
-		for node in select!(Vec<Node> "ORDER by RANDOM() LIMIT 100").unwrap().into_iter() {
-			let host = node.host.as_ref().unwrap();
-			// rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut target);
+	/// Run `task` every `interval` until the node is dropped or the returned handle aborts it.
+	fn every<F, Fut>(&self, interval: std::time::Duration, task: F) -> tokio::task::AbortHandle
+	where
+		F: Fn(Dht) -> Fut + Send + 'static,
+		Fut: std::future::Future<Output = ()> + Send,
+	{
+		let dht = std::sync::Arc::downgrade(&self.0);
+		let handle = tokio::spawn(async move {
+			loop {
+				tokio::time::sleep(interval).await;
+				let Some(dht) = dht.upgrade() else { return };
+				task(Dht(dht)).await;
+			}
+		});
+		let mut tasks = self.0.tasks.lock().unwrap();
+		tasks.retain(|task| !task.is_finished());
+		tasks.push(handle.abort_handle());
+		handle.abort_handle()
+	}
+
+	/// Count a responder's view of our IP, and switch to a new node id if the consensus changes.
+	fn vote_external_ip(&self, voter: std::net::SocketAddr, reported: std::net::SocketAddr) {
+		// Our node id is derived from our IPv4 address.
+		if self.0.external_ip_override.is_some() || !voter.is_ipv4() || !reported.is_ipv4() {
+			return;
+		}
+		let Some(ip) = self.0.ip_voter.lock().unwrap().vote(voter.ip(), reported.ip()) else { return };
+		if *self.0.external_ip.lock().unwrap() == Some(ip) {
+			return;
+		}
 
-			SOCK
-				.get()
-				.unwrap()
-				.send_to(
-					&SampleInfohashesQuery { id: *SELF_ID.get().unwrap(), target: info_hash }.into_bytes(),
-					&host,
-				)
-				.await
-				.ok();
+		// Identities keep their slice of the keyspace whatever our IP.
+		if self.0.identity.is_some() {
+			info!("external ip is now {:?}", ip);
+			*self.0.external_ip.lock().unwrap() = Some(ip);
+			return;
 		}
 
-		yield progress!("loading for infohash {infohash}");
+		let dht = self.clone();
+		tokio::task::spawn_blocking(move || {
+			let id = if dht.0.persistent {
+				match self_id_for_ip(ip) {
+					Ok(id) => id,
+					Err(e) => return warn!("self_id_for_ip error: {:?}", e),
+				}
+			} else {
+				id_from_ip(&ip)
+			};
+			info!("external ip is now {:?}, node id {}", ip, hex::encode(id));
+			*dht.0.external_ip.lock().unwrap() = Some(ip);
+			*dht.0.self_id.write().unwrap() = id;
+			dht.routing_table(false).set_self_id(id);
+			dht.routing_table(true).set_self_id(id);
+		});
+	}
+
+	/// Our external IP, as we were told or as other nodes agree on.
+	pub fn external_ip(&self) -> Option<std::net::IpAddr> {
+		*self.0.external_ip.lock().unwrap()
+	}
 
-		// err!("ohno")?;
-		// 	// yield complete!({
-		// 	// 	result: "datagoeshere",
-		// 	// 	status: "loading complete for infohash {infohash}",
-		// 	// });
+	/// The DHT socket for an address family, if we have one.
+	fn sock(&self, ipv6: bool) -> std::io::Result<&dyn DatagramSocket> {
+		let sock = if ipv6 { self.0.sock6.as_deref() } else { Some(&*self.0.sock) };
+		sock.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Unsupported, "no socket for family"))
+	}
 
-		let mut receiver = BROADCAST.subscribe();
+	/// Address families we run a DHT on: `false` for IPv4, `true` for IPv6.
+	fn families(&self) -> Vec<bool> {
+		if self.0.sock6.is_some() {
+			vec![false, true]
+		} else {
+			vec![false]
+		}
+	}
 
-		for node in
-			select!(Vec<Node> "WHERE rowid IN (SELECT rowid FROM node ORDER BY RANDOM() LIMIT 40)")?
-				.into_iter()
-		{
-			our_hosts.insert(node.host.clone().unwrap());
+	async fn handle_packet(&self, addr: std::net::SocketAddr, packet: &[u8]) {
+		// println!(
+		// 	"{:?} bytes received from {:?}: {:?}",
+		// 	len,
+		// 	addr,
+		// 	String::from_utf8_lossy(&buf[..len])
+		// );
+
+		match KrpcMessage::from_bytes(packet) {
+			Ok(KrpcMessage::Response(Response { t, r: response, ip })) => {
+				if let Some(reported) = ip.as_ref().and_then(Peer::addr) {
+					self.vote_external_ip(addr, reported);
+				}
+
+				if response.id.len() == 20 {
+					let stale =
+						self.routing_table(addr.is_ipv6()).heard_from(response.id(), addr, Heard::Response);
+					if let Some(stale) = stale {
+						let dht = self.clone();
+						tokio::spawn(async move { dht.ping(stale.addr).await });
+					}
+				}
 
-			SOCK
-				.get()
-				.unwrap()
-				.send_to(
-					&GetPeersQuery { id: self_id!(), info_hash }.into_bytes(),
-					&node.host.as_ref().unwrap(),
-				)
-				.await?;
+				// Don't let nodes with forged ids feed the crawl when we've been told not to trust them.
+				let forged = response.id.len() != 20 || !verify_node_id(&addr.ip(), &response.id());
+				let trusted = !(forged && self.routing_table(addr.is_ipv6()).policy() == NodeIdPolicy::Require);
+				if trusted && self.0.persistent {
+					let cloned = response.clone();
+					let dht = self.clone();
+					let id = self.self_id();
+					tokio::task::spawn_blocking(move || match process_response(addr.to_string(), cloned, id) {
+						Ok(new) => {
+							dht.0.new_infohashes.fetch_add(new, std::sync::atomic::Ordering::Relaxed);
+						}
+						Err(e) => warn!("process_response error: {:?}", e),
+					});
+				}
 
-			packets_sent += 1;
+				self.0.transactions.lock().unwrap().complete(&t, addr, Ok(response));
+			}
+			Ok(KrpcMessage::Error(IncomingError { t, error, ip })) => {
+				if let Some(reported) = ip.as_ref().and_then(Peer::addr) {
+					self.vote_external_ip(addr, reported);
+				}
+				debug!("error {} from {:?}: {}", error.code, addr, error.message);
+				self.routing_table(addr.is_ipv6()).errored(&addr);
+				self.0.transactions.lock().unwrap().complete(&t, addr, Err(error));
+			}
+			Ok(KrpcMessage::Query(_)) if self.0.read_only => {}
+			Ok(KrpcMessage::Query(query)) => {
+				let (reply, stale) = self.handle_query(addr, query);
+				// Replies can't wait for the limiter without stalling the receive loop, so drop them instead.
+				if self.try_throttle(&addr) {
+					if let Ok(sock) = self.sock(addr.is_ipv6()) {
+						sock.send_to(&reply, addr).await.ok();
+					}
+				}
+				if let Some(stale) = stale {
+					let dht = self.clone();
+					tokio::spawn(async move { dht.ping(stale.addr).await });
+				}
+			}
+			Err(_) => {}
 		}
+	}
 
-		let tout = std::time::Duration::from_secs(1);
-		use tokio::time::timeout;
+	/// Fill an empty routing table: ask the routers for nodes near our own id, then look
+	/// up our own id through those nodes, and persist whatever we found.
+	async fn bootstrap(&self, ipv6: bool) {
+		let family = if ipv6 { "IPv6" } else { "IPv4" };
+		let set_status =
+			|status: BootstrapStatus| self.0.bootstrap.lock().unwrap()[ipv6 as usize] = status;
+		if self.bootstrap_status(ipv6) == BootstrapStatus::Running {
+			return;
+		}
+		set_status(BootstrapStatus::Running);
+		info!("bootstrapping {family} DHT");
+
+		let mut routers = Vec::new();
+		for host in &self.0.routers {
+			match tokio::net::lookup_host(host.as_str()).await {
+				Ok(addrs) => routers.extend(addrs.filter(|addr| addr.is_ipv6() == ipv6)),
+				Err(e) => warn!("could not resolve router {host}: {e}"),
+			}
+		}
+
+		let target = self.self_id();
+		let mut nodes = Vec::new();
+		for _ in 0..BOOTSTRAP_ATTEMPTS {
+			let queries = routers.iter().map(|&router| {
+				self.request(router, move |t, ro| FindNodeQuery { id: target, target }.into_bytes(t, ro))
+			});
+			nodes = futures::future::join_all(queries).await.into_iter().flatten().collect();
+			if !nodes.is_empty() || routers.is_empty() {
+				break;
+			}
+		}
+		let pings =
+			nodes.iter().flat_map(|response| response.node_addrs(ipv6)).map(|(_, addr)| self.ping(addr));
+		futures::future::join_all(pings).await;
+
+		self.find_node(target, ipv6).await;
+
+		// Looking up our own id only finds nodes near us; fill in the far buckets too, so lookups
+		// for distant targets have somewhere to start.
+		let far = self.routing_table(ipv6).empty_far_buckets();
+		let targets: Vec<_> =
+			far.into_iter().map(|i| self.routing_table(ipv6).random_id_in_bucket(i)).collect();
+		futures::future::join_all(targets.into_iter().map(|target| self.find_node(target, ipv6))).await;
+
+		let nodes: Vec<_> = self.routing_table(ipv6).nodes().cloned().collect();
+		let status = if nodes.is_empty() {
+			let reason = format!("no nodes found via {} {family} routers", routers.len());
+			error!("bootstrap failed: {reason}");
+			BootstrapStatus::Failed(reason)
+		} else {
+			info!("bootstrapped {family} DHT with {} nodes", nodes.len());
+			BootstrapStatus::Done(nodes.len())
+		};
+		set_status(status);
+
+		if self.0.persistent {
+			tokio::task::spawn_blocking(move || {
+				if let Err(e) = save_routing_table(nodes) {
+					warn!("save_routing_table error: {:?}", e);
+				}
+			});
+		}
+	}
+
+	/// How bootstrapping the IPv4 or IPv6 routing table went.
+	pub fn bootstrap_status(&self, ipv6: bool) -> BootstrapStatus {
+		self.0.bootstrap.lock().unwrap()[ipv6 as usize].clone()
+	}
+
+	/// Evict unresponsive nodes, refresh quiet buckets, and persist the table.
+	async fn maintain_routing_table(&self) {
+		self.0.peer_store.lock().unwrap().expire();
+		self.0.items.lock().unwrap().expire();
+		self.0.rate_limiter.lock().unwrap().prune();
+
+		let mut nodes = Vec::new();
+
+		for ipv6 in [false, true] {
+			let targets = {
+				let mut table = self.routing_table(ipv6);
+				table.expire_pending();
+				nodes.extend(table.nodes().cloned());
+				table.take_stale_buckets().into_iter().map(|i| table.random_id_in_bucket(i)).collect::<Vec<_>>()
+			};
+
+			if self.sock(ipv6).is_ok() {
+				for target in targets {
+					let dht = self.clone();
+					tokio::spawn(async move { dht.find_node(target, ipv6).await });
+				}
+				// We lost every node, e.g. after the network went away; start over.
+				if self.routing_table(ipv6).len() == 0 {
+					let dht = self.clone();
+					tokio::spawn(async move { dht.bootstrap(ipv6).await });
+				}
+			}
+		}
+
+		if self.0.persistent {
+			tokio::task::spawn_blocking(move || {
+				if let Err(e) = save_routing_table(nodes) {
+					warn!("save_routing_table error: {:?}", e);
+				}
+			});
+		}
+	}
+
+	/// Ping the nodes in the node table we haven't heard from lately, record how they did,
+	/// and delete the ones that keep failing, so the table is still useful after a restart.
+	async fn maintain_node_table(&self) -> Result<(), Box<dyn std::error::Error>> {
+		let stale_before = now_ms() - NODE_STALE_AFTER.as_millis() as i64;
+		let nodes = tokio::task::spawn_blocking(move || {
+			select!(Vec<Node>
+				"WHERE last_response_ms IS NULL OR last_response_ms < " stale_before
+				"ORDER BY last_ping_attempt_ms LIMIT 100"
+			)
+		})
+		.await??;
+
+		let pings = nodes.into_iter().filter_map(|node| {
+			let addr: std::net::SocketAddr = node.host.as_ref()?.parse().ok()?;
+			Some(async move {
+				let started = std::time::Instant::now();
+				let result = self.ping(addr).await;
+				(node, addr, started.elapsed(), result)
+			})
+		});
+		let results = futures::future::join_all(pings).await;
+
+		tokio::task::spawn_blocking(move || -> Result<(), turbosql::Error> {
+			let now = now_ms();
+			execute!("BEGIN TRANSACTION")?;
+			for (node, addr, rtt, result) in results {
+				let host = node.host.unwrap_or_default();
+				let id = match &result {
+					Ok(response) if response.id.len() == 20 => Some(response.id()),
+					_ => node.id,
+				};
+				match result {
+					// An error reply still shows the node is alive.
+					Ok(_) | Err(RequestError::Krpc(_)) => {
+						let rtt_ms = rtt.as_millis() as i64;
+						let bep42 = id.map(|id| verify_node_id(&addr.ip(), &id));
+						execute!(
+							"UPDATE node SET"
+								"id = " id,
+								"bep42 = " bep42,
+								"last_ping_attempt_ms = " now,
+								"last_ping_response_ms = " now,
+								"last_response_ms = " now,
+								"rtt_ms = " rtt_ms,
+								"failures = 0"
+							"WHERE host = " host
+						)?;
+					}
+					Err(_) => {
+						execute!(
+							"UPDATE node SET"
+								"last_ping_attempt_ms = " now,
+								"failures = COALESCE(failures, 0) + 1"
+							"WHERE host = " host
+						)?;
+					}
+				}
+			}
+			let pruned = execute!("DELETE FROM node WHERE failures >= " NODE_MAX_FAILURES)?;
+			execute!("COMMIT")?;
+			if pruned > 0 {
+				info!("pruned {pruned} dead nodes from the node table");
+			}
+			Ok(())
+		})
+		.await??;
+
+		Ok(())
+	}
+
+	/// Build our reply to a query from another node.
+	///
+	/// Also returns a routing table node to ping if the querying node is waiting on its slot.
+	fn handle_query(
+		&self,
+		addr: std::net::SocketAddr,
+		query: IncomingQuery,
+	) -> (Vec<u8>, Option<RoutingNode>) {
+		let IncomingQuery { t, q, a, ro, .. } = query;
+		// BEP 43: read-only nodes won't answer our queries, so they don't belong in the table.
+		let stale = match ro {
+			Some(1) => None,
+			_ => self.routing_table(addr.is_ipv6()).heard_from(a.id, addr, Heard::Query),
+		};
+
+		let args = ReplyArgs { id: self.self_id(), ..Default::default() };
+
+		// BEP 32: without `want`, reply with nodes of the querier's own address family.
+		let want = |family: &str| match &a.want {
+			Some(want) => want.iter().any(|w| w == family),
+			None => addr.is_ipv6() == (family == "n6"),
+		};
+		let nodes = |target: &[u8; 20]| ReplyArgs {
+			nodes: want("n4").then(|| compact_nodes(&self.routing_table(false).closest(target, K))),
+			nodes6: want("n6").then(|| compact_nodes6(&self.routing_table(true).closest(target, K))),
+			..Default::default()
+		};
+
+		let reply = match q.as_str() {
+			"ping" => args.into_bytes(t),
+			"find_node" => match a.target() {
+				Some(target) => ReplyArgs { id: args.id, ..nodes(&target) }.into_bytes(t),
+				None => ErrorReply { code: 203, message: "missing target".into() }.into_bytes(t),
+			},
+			"get_peers" => match a.info_hash() {
+				Some(info_hash) => {
+					self.observed_infohash(info_hash);
+					let token = Some(self.0.tokens.lock().unwrap().token_for(&addr.ip()));
+					let peer_store = self.0.peer_store.lock().unwrap();
+					let values = peer_store.get(&info_hash, 50, a.noseed == Some(1));
+					let values = values.iter().filter(|peer| peer.is_ipv6() == addr.is_ipv6());
+					let values: Vec<_> = values.map(compact_peer).collect();
+					let (bfsd, bfpe) = match a.scrape {
+						Some(1) => {
+							let (seeds, peers) = peer_store.scrape(&info_hash);
+							(Some(seeds.as_bytes().to_vec()), Some(peers.as_bytes().to_vec()))
+						}
+						_ => (None, None),
+					};
+					drop(peer_store);
+					if values.is_empty() {
+						ReplyArgs { id: args.id, token, bfsd, bfpe, ..nodes(&info_hash) }.into_bytes(t)
+					} else {
+						ReplyArgs { values: Some(values), token, bfsd, bfpe, ..args }.into_bytes(t)
+					}
+				}
+				None => ErrorReply { code: 203, message: "missing info_hash".into() }.into_bytes(t),
+			},
+			"announce_peer" => {
+				let Bytes::Bytes(token) = a.token.as_ref().unwrap_or_default();
+				match a.info_hash() {
+					Some(info_hash) if self.0.tokens.lock().unwrap().verify(&addr.ip(), token) => {
+						let port = match (a.implied_port, a.port) {
+							(Some(1), _) | (_, None) => addr.port(),
+							(_, Some(port)) => port,
+						};
+						self.observed_infohash(info_hash);
+						let seed = a.seed == Some(1);
+						self.0.peer_store.lock().unwrap().announce(info_hash, (addr.ip(), port).into(), seed);
+						args.into_bytes(t)
+					}
+					Some(_) => ErrorReply { code: 203, message: "bad token".into() }.into_bytes(t),
+					None => ErrorReply { code: 203, message: "missing info_hash".into() }.into_bytes(t),
+				}
+			}
+			"get" => match a.target() {
+				Some(target) => {
+					let token = Some(self.0.tokens.lock().unwrap().token_for(&addr.ip()));
+					let reply = ReplyArgs { id: args.id, token, ..nodes(&target) };
+					match self.0.items.lock().unwrap().get(&target, a.seq) {
+						Some(item) => reply.with_item(item).into_bytes(t),
+						None => reply.into_bytes(t),
+					}
+				}
+				None => ErrorReply { code: 203, message: "missing target".into() }.into_bytes(t),
+			},
+			"put" => {
+				let Bytes::Bytes(token) = a.token.as_ref().unwrap_or_default();
+				if self.0.tokens.lock().unwrap().verify(&addr.ip(), token) {
+					let stored = a.item().and_then(|item| {
+						item.validate()?;
+						self.0.items.lock().unwrap().put(item, a.cas)
+					});
+					match stored {
+						Ok(()) => args.into_bytes(t),
+						Err(error) => error.into_bytes(t),
+					}
+				} else {
+					ErrorReply { code: 203, message: "bad token".into() }.into_bytes(t)
+				}
+			}
+			_ => ErrorReply { code: 204, message: "Method Unknown".into() }.into_bytes(t),
+		};
+
+		(reply, stale)
+	}
+
+	/// Remember an infohash that another node asked about or announced.
+	fn observed_infohash(&self, info_hash: [u8; 20]) {
+		if !self.0.persistent {
+			return;
+		}
+		tokio::task::spawn_blocking(move || {
+			if let Err(e) = execute!("INSERT OR IGNORE INTO infohash(infohash) VALUES (" info_hash ")") {
+				warn!("observed_infohash error: {:?}", e);
+			}
+		});
+	}
+
+	/// Good, questionable and bad node counts for each non-empty bucket of the IPv4 or IPv6
+	/// routing table.
+	pub fn routing_table_stats(&self, ipv6: bool) -> Vec<(usize, BucketStats)> {
+		self.routing_table(ipv6).stats()
+	}
 
+	/// Wait until the rate limiter lets us send a packet to `addr`.
+	async fn throttle(&self, addr: &std::net::SocketAddr) {
+		let mut throttled = false;
 		loop {
-			yield progress!(
-				"loading dht for infohash {infohash}; sent {packets_sent}, recv {packets_recv}, peers {}",
-				(peers.len())
-			);
-
-			let Ok(Ok((addr, response))) = timeout(tout, receiver.recv()).await else {
-				println!("sent {packets_sent}, recv {packets_recv}");
-				let finished = peers.values().fold(0, |acc, peer:&tokio::task::JoinHandle<_>| acc + peer.is_finished() as usize);
-				println!("tcp started {}, finished {finished}", peers.len());
-				if finished == peers.len() { return; }
-				continue;
+			let wait = {
+				let mut limiter = self.0.rate_limiter.lock().unwrap();
+				match limiter.acquire(addr) {
+					Ok(()) => return,
+					Err(wait) => {
+						if !std::mem::replace(&mut throttled, true) {
+							limiter.throttled += 1;
+						}
+						wait
+					}
+				}
 			};
+			tokio::time::sleep(wait).await;
+		}
+	}
+
+	/// Take a token for a packet to `addr` without waiting. Returns `false` if the packet
+	/// should be dropped.
+	fn try_throttle(&self, addr: &std::net::SocketAddr) -> bool {
+		let mut limiter = self.0.rate_limiter.lock().unwrap();
+		let allowed = limiter.acquire(addr).is_ok();
+		if !allowed {
+			limiter.dropped += 1;
+		}
+		allowed
+	}
+
+	/// Whether queries are queueing behind the rate limiter.
+	fn congested(&self) -> bool {
+		self.0.rate_limiter.lock().unwrap().congested()
+	}
+
+	/// Queries that had to wait for the rate limiter, and replies it dropped, since launch.
+	pub fn rate_limit_stats(&self) -> (u64, u64) {
+		let limiter = self.0.rate_limiter.lock().unwrap();
+		(limiter.throttled, limiter.dropped)
+	}
+
+	/// Send a query to `addr` and wait for the response carrying its transaction id.
+	///
+	/// `query` builds the packet from the transaction id we allocated and whether we're
+	/// read-only. We wait for the rate limiter before sending; [`QUERY_TIMEOUT`] only starts
+	/// once the query is sent.
+	pub async fn request(
+		&self,
+		addr: std::net::SocketAddr,
+		query: impl FnOnce(&[u8], bool) -> Vec<u8>,
+	) -> Result<ResponseArgs, RequestError> {
+		self.throttle(&addr).await;
+		let started = self.0.transactions.lock().unwrap().start(addr);
+		let (t, receiver) = started.ok_or(RequestError::NoTransactionId)?;
+		let transactions = &self.0.transactions;
+		let mut pending = Pending { transactions, t, sent: false, finished: false };
+		self.routing_table(addr.is_ipv6()).queried(&addr);
+
+		let sent = match self.sock(addr.is_ipv6()) {
+			Ok(sock) => sock.send_to(&query(&t, self.0.read_only), addr).await,
+			Err(e) => Err(e),
+		};
+		sent.map_err(RequestError::Send)?;
+		pending.sent = true;
+
+		let started = std::time::Instant::now();
+		match tokio::time::timeout(QUERY_TIMEOUT, receiver).await {
+			Ok(Ok(reply)) => {
+				pending.finished = true;
+				self.routing_table(addr.is_ipv6()).responded(&addr, started.elapsed());
+				reply.map_err(RequestError::Krpc)
+			}
+			_ => {
+				drop(pending);
+				self.routing_table(addr.is_ipv6()).failed(&addr);
+				Err(RequestError::Timeout)
+			}
+		}
+	}
+
+	pub async fn ping(&self, addr: std::net::SocketAddr) -> Result<ResponseArgs, RequestError> {
+		let id = self.self_id();
+		self.request(addr, |t, ro| PingQuery { id }.into_bytes(t, ro)).await
+	}
 
-			packets_recv += 1;
+	/// Queries sent, answered, answered with an error, and timed out since launch.
+	pub fn transaction_stats(&self) -> (u64, u64, u64, u64) {
+		let transactions = self.0.transactions.lock().unwrap();
+		(transactions.sent, transactions.answered, transactions.errors, transactions.timed_out)
+	}
 
-			// println!("{:?}", addr);
+	/// Look up peers for `infohash` (hex), and fetch the torrent's info dictionary from them.
+	#[tracked::tracked]
+	pub fn get_peers(&self, infohash: impl Into<String>) -> ProgressStream<GetPeersResult> {
+		let infohash = infohash.into();
+		let dht = self.clone();
+		Box::pin(async_stream::try_stream! {
+			// let mut target = [0u8; 20];
+
+			let mut packets_recv = 0;
+			let mut peers = HashMap::new();
+
+			let info_hash: [u8; 20] =
+				hex::decode(&infohash)?.try_into().map_err(|_| "infohash not 20 hex bytes")?;
+
+			let metainfo = MetaInfo::new(info_hash);
+
+			yield progress!("loading for infohash {infohash}");
+
+			let families = dht.families();
+			let lookups = families.iter().map(|&ipv6| dht.lookup(info_hash, LookupKind::GetPeers, ipv6));
+			let mut lookups = futures::stream::select_all(lookups);
+			let mut closest = Vec::new();
+			let mut complete = 0;
+
+			while let Some(event) = lookups.next().await {
+				let response = match event {
+					LookupEvent::Response { response, .. } => response,
+					LookupEvent::Complete { closest: nodes } => {
+						closest.extend(nodes);
+						complete += 1;
+						if complete == families.len() {
+							break;
+						}
+						continue;
+					}
+				};
 
-			// if our_hosts.contains(&addr) {
-			for node in response.nodes() {
-				if our_hosts.insert(node.host()) {
-					SOCK
-						.get()
-						.unwrap()
-						.send_to(&GetPeersQuery { id: self_id!(), info_hash }.into_bytes(), &node.host())
-						.await
-						.map_err(|e| warn!("{:?} {:?}", e, node.host()))
-						.ok();
+				packets_recv += 1;
+
+				if let Some(values) = response.values {
+					for peer in values {
+						let Some(addr) = peer.addr() else { continue };
+						peers.entry(addr).or_insert_with(|| {
+							let metainfo = metainfo.clone();
+							let dht = dht.clone();
+							tokio::spawn(async move {
+								dht.run_peer(addr, metainfo).await;
+							})
+						});
+					}
+				}
+
+				yield progress!(
+					"loading dht for infohash {infohash}; recv {packets_recv}, peers {}",
+					(peers.len())
+				);
+			}
 
-					packets_sent += 1;
+			loop {
+				let finished = peers.values().filter(|peer| peer.is_finished()).count();
+				if finished == peers.len() {
+					break;
 				}
+				yield progress!(
+					"lookup converged for infohash {infohash}; tcp started {}, finished {finished}",
+					(peers.len())
+				);
+				tokio::time::sleep(std::time::Duration::from_secs(1)).await;
 			}
 
-			if let Some(values) = response.values {
-				for peer in values {
-					let host = peer.host();
-					peers.entry(host.clone()).or_insert_with(|| {
-						let metainfo = metainfo.clone();
-						tokio::spawn(async move {
-							run_peer(host, metainfo).await;
-						})
+			let metadata = metainfo.data().await;
+			let result = GetPeersResult { closest, metadata, peers: peers.keys().copied().collect() };
+			yield complete!(result, "loading complete for infohash {infohash}; peers {}", (peers.len()));
+		})
+	}
+
+	/// Run an iterative lookup for `target` over the IPv4 or IPv6 DHT, seeded from that
+	/// family's routing table.
+	pub fn lookup(
+		&self,
+		target: [u8; 20],
+		kind: LookupKind,
+		ipv6: bool,
+	) -> std::pin::Pin<Box<dyn futures::Stream<Item = LookupEvent> + Send>> {
+		let dht = self.clone();
+		Box::pin(async_stream::stream! {
+			let dht = &dht;
+			let (seeds, policy) = {
+				let table = dht.routing_table(ipv6);
+				(table.closest(&target, K * 2), table.policy())
+			};
+			let mut state = Lookup::new(target, kind, ipv6, policy, seeds);
+			let mut in_flight = futures::stream::FuturesUnordered::new();
+
+			loop {
+				let parallelism = if dht.congested() { 1 } else { ALPHA };
+				for addr in state.next_queries(parallelism) {
+					in_flight.push(async move {
+						let id = dht.self_id();
+						(addr, dht.request(addr, |t, ro| lookup_query(kind, id, target, t, ro)).await)
 					});
 				}
+
+				// Queries still in flight are forgotten when `in_flight` is dropped.
+				if state.is_done() {
+					break;
+				}
+
+				let Some((addr, response)) = in_flight.next().await else { break };
+
+				match response {
+					Ok(response) => {
+						if state.on_response(addr, &response) {
+							yield LookupEvent::Response { addr, response: Box::new(response) };
+						}
+					}
+					Err(_) => state.on_failure(addr),
+				}
+			}
+
+			yield LookupEvent::Complete { closest: state.closest() };
+		})
+	}
+
+	/// Run a lookup to completion, returning the K closest nodes that answered.
+	async fn lookup_closest(&self, target: [u8; 20], kind: LookupKind, ipv6: bool) -> Vec<LookupNode> {
+		let mut lookup = self.lookup(target, kind, ipv6);
+		while let Some(event) = lookup.next().await {
+			if let LookupEvent::Complete { closest } = event {
+				return closest;
 			}
 		}
-		// }
+		Vec::new()
+	}
 
-		// yield complete! {
-		// 	status: "loading complete for infohash",
-		// 	result: "datagoeshere"
-		// };
-		// 	// yield complete!("done", "loading for infohash {infohash}");
-	})
-}
+	pub async fn find_node(&self, target: [u8; 20], ipv6: bool) -> Vec<LookupNode> {
+		self.lookup_closest(target, LookupKind::FindNode, ipv6).await
+	}
+
+	/// Find the nodes closest to `info_hash` on each address family we run, and announce that
+	/// we're a peer on `port` using the write tokens they handed out. Returns the nodes that
+	/// accepted the announce.
+	///
+	/// With `implied_port`, nodes use the source port of the announce instead of `port`. `seed`
+	/// says we have all of the torrent, for BEP 33 scrapes.
+	pub async fn announce_peer(
+		&self,
+		info_hash: [u8; 20],
+		port: u16,
+		implied_port: bool,
+		seed: bool,
+	) -> Vec<LookupNode> {
+		let lookups = self
+			.families()
+			.into_iter()
+			.map(|ipv6| self.lookup_closest(info_hash, LookupKind::GetPeers, ipv6));
+		let closest = futures::future::join_all(lookups).await.into_iter().flatten();
+
+		let announces = closest.filter(|node| node.token.is_some()).map(|node| async move {
+			let (id, token) = (self.self_id(), node.token.clone().unwrap());
+			let implied_port = implied_port as u8;
+			let query = |t: &[u8], ro| {
+				let seed = seed.then_some(1);
+				AnnouncePeerQuery { id, implied_port, info_hash, port, seed, token }.into_bytes(t, ro)
+			};
+			self.request(node.addr, query).await.ok().map(|_| node)
+		});
 
-async fn run_peer(host: String, metainfo: MetaInfo) {
-	let tout = std::time::Duration::from_secs(5);
-	use tokio::time::timeout;
-	info!("connecting {:?}", host);
-	let socket = TcpSocket::new_v4().unwrap();
-	#[cfg(all(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
-	if let Some(Some(interface)) = INTERFACE.get() {
-		socket.bind_device(Some(interface.as_bytes())).unwrap();
+		let accepted: Vec<_> = futures::future::join_all(announces).await.into_iter().flatten().collect();
+		info!("announced {} to {} nodes", hex::encode(info_hash), accepted.len());
+		accepted
 	}
-	let Ok(Ok(mut s)) = timeout(tout, socket.connect(host.parse().unwrap())).await else { info!("failed {:?}", host); return; };
-	info!("CONNECTED {:?}", host);
-	let (rx, mut tx) = s.split();
-	let mut rx = tokio::io::BufReader::new(rx);
 
-	let mut remote_extension_id = None;
+	/// Announce `info_hash` now and every [`REANNOUNCE_INTERVAL`] until [`Dht::stop_seeding`],
+	/// as a seed if `seed`; call again to change how it's announced.
+	pub fn start_seeding(&self, info_hash: [u8; 20], port: u16, implied_port: bool, seed: bool) {
+		self.0.seeding.lock().unwrap().insert(info_hash, (port, implied_port, seed));
+		let dht = self.clone();
+		tokio::spawn(async move { dht.announce_peer(info_hash, port, implied_port, seed).await });
+	}
 
-	tx
-		.write_all(&Handshake { info_hash: metainfo.infohash(), peer_id: self_id!() }.to_bytes())
-		.await
-		.unwrap();
+	pub fn stop_seeding(&self, info_hash: &[u8; 20]) {
+		self.0.seeding.lock().unwrap().remove(info_hash);
+	}
 
-	let mut handshake = [0; 68];
-	let Ok(Ok(_)) = timeout(tout, rx.read_exact(&mut handshake)).await else { info!("handshake failed {:?}", host); return; };
+	/// BEP 33: estimate the seeders and leechers of `info_hash` by merging the bloom filters
+	/// of the nodes closest to it, and record the estimate.
+	pub async fn scrape(&self, info_hash: [u8; 20]) -> (i64, i64) {
+		let (mut seeds, mut peers) = (BloomFilter::default(), BloomFilter::default());
+
+		for ipv6 in self.families() {
+			let mut filters = HashMap::new();
+			let mut lookup = self.lookup(info_hash, LookupKind::Scrape, ipv6);
+			while let Some(event) = lookup.next().await {
+				match event {
+					LookupEvent::Response { addr, response } => {
+						if let Some(pair) = response.bloom_filters() {
+							filters.insert(addr, pair);
+						}
+					}
+					LookupEvent::Complete { closest } => {
+						for (node_seeds, node_peers) in closest.iter().filter_map(|n| filters.get(&n.addr)) {
+							seeds.merge(node_seeds);
+							peers.merge(node_peers);
+						}
+					}
+				}
+			}
+		}
 
-	loop {
-		let Ok(len) = rx.read_u32().await else { return };
-		let len = len as usize;
+		let (seeders, leechers) = (seeds.estimate().round() as i64, peers.estimate().round() as i64);
+		info!("scraped {}: ~{} seeders, ~{} leechers", hex::encode(info_hash), seeders, leechers);
+
+		if self.0.persistent {
+			tokio::task::spawn_blocking(move || {
+				let scrape = Scrape {
+					rowid: None,
+					infohash: Some(info_hash),
+					seeders: Some(seeders),
+					leechers: Some(leechers),
+					scraped_ms: Some(now_ms()),
+				};
+				if let Err(e) = scrape.insert() {
+					warn!("scrape insert error: {:?}", e);
+				}
+			});
+		}
+
+		(seeders, leechers)
+	}
 
-		if len == 0 {
-			info!("len 0 from {}", host);
-			continue;
+	/// Look up the BEP 44 item stored at `target`.
+	///
+	/// Mutable items are only accepted if their signature checks out; `salt` must be the one
+	/// they were put with, and the one with the highest `seq` wins.
+	pub async fn get_item(&self, target: [u8; 20], salt: Vec<u8>) -> Option<Item> {
+		let lookups = self.families().into_iter().map(|ipv6| self.lookup(target, LookupKind::Get, ipv6));
+		let mut lookups = futures::stream::select_all(lookups);
+		let mut found: Option<Item> = None;
+
+		while let Some(event) = lookups.next().await {
+			let LookupEvent::Response { response, .. } = event else { continue };
+			let Some(item) = response.item(&target, &salt) else { continue };
+			match item.seq() {
+				None => return Some(item),
+				Some(seq) if found.as_ref().and_then(Item::seq).is_none_or(|found| seq > found) => {
+					found = Some(item);
+				}
+				Some(_) => {}
+			}
 		}
 
-		if len > 32768 {
-			panic!("len > 32768");
+		found
+	}
+
+	/// Look up an immutable item by the SHA-1 of its bencoded value, returning that value.
+	pub async fn get_immutable(&self, target: [u8; 20]) -> Option<Vec<u8>> {
+		self.get_item(target, Vec::new()).await.map(|item| item.v().to_vec())
+	}
+
+	/// Look up the latest mutable item signed by public key `k` under `salt`.
+	pub async fn get_mutable(&self, k: [u8; 32], salt: Vec<u8>) -> Option<Item> {
+		self.get_item(mutable_target(&k, &salt), salt).await
+	}
+
+	/// Store `item` on the nodes closest to its target, using the write tokens from a `get`
+	/// lookup. Returns the nodes that accepted it.
+	///
+	/// `cas` makes nodes refuse a mutable put unless the `seq` they hold matches. Errors if the
+	/// item isn't one nodes would accept: a value that isn't bencoded, is too big, or has a bad
+	/// signature.
+	#[tracked::tracked]
+	pub async fn put_item(
+		&self,
+		item: Item,
+		cas: Option<i64>,
+	) -> Result<Vec<LookupNode>, tracked::StringError> {
+		let v: serde_bencode::value::Value = serde_bencode::from_bytes(item.v())?;
+		item.validate().map_err(|e| e.message)?;
+		let target = item.target();
+		let lookups =
+			self.families().into_iter().map(|ipv6| self.lookup_closest(target, LookupKind::Get, ipv6));
+		let closest = futures::future::join_all(lookups).await.into_iter().flatten();
+
+		let puts = closest.filter(|node| node.token.is_some()).map(|node| {
+			let (item, v) = (&item, v.clone());
+			async move {
+				let (id, token) = (self.self_id(), node.token.clone().unwrap());
+				let query = |t: &[u8], ro| PutQuery::new(id, token, item, v, cas).into_bytes(t, ro);
+				match self.request(node.addr, query).await {
+					Ok(_) => Some(node),
+					Err(e) => {
+						debug!("put to {:?} failed: {:?}", node.addr, e);
+						None
+					}
+				}
+			}
+		});
+
+		let accepted: Vec<_> = futures::future::join_all(puts).await.into_iter().flatten().collect();
+		info!("put {} to {} nodes", hex::encode(target), accepted.len());
+		Ok(accepted)
+	}
+
+	/// Store a bencoded value as an immutable item, returning its target.
+	#[tracked::tracked]
+	pub async fn put_immutable(&self, v: Vec<u8>) -> Result<[u8; 20], tracked::StringError> {
+		let item = Item::Immutable { v };
+		let target = item.target();
+		self.put_item(item, None).await?;
+		Ok(target)
+	}
+
+	/// Sign and store a bencoded value as a mutable item under `key` and `salt`.
+	pub async fn put_mutable(
+		&self,
+		key: &ed25519_dalek::SigningKey,
+		salt: Vec<u8>,
+		seq: i64,
+		v: Vec<u8>,
+		cas: Option<i64>,
+	) -> Result<Vec<LookupNode>, tracked::StringError> {
+		self.put_item(Item::mutable(key, salt, seq, v), cas).await
+	}
+
+	/// Resolve a BEP 46 `magnet:?xs=urn:btpk:` link to the infohash it currently points at.
+	#[tracked::tracked]
+	pub async fn resolve_magnet(&self, uri: &str) -> Result<[u8; 20], tracked::StringError> {
+		let magnet = MutableMagnet::parse(uri).ok_or("not a magnet:?xs=urn:btpk: link")?;
+		let item =
+			self.get_mutable(magnet.k, magnet.salt).await.ok_or("no item found for magnet link")?;
+		Ok(pointer_infohash(item.v()).ok_or("item doesn't point at an infohash")?)
+	}
+
+	/// Point the BEP 46 link for `key` and `salt` at `info_hash`, and put it again every
+	/// [`REANNOUNCE_INTERVAL`] so it doesn't expire. Returns the link.
+	#[tracked::tracked]
+	pub async fn publish_torrent(
+		&self,
+		key: ed25519_dalek::SigningKey,
+		salt: Vec<u8>,
+		info_hash: [u8; 20],
+	) -> Result<String, tracked::StringError> {
+		let magnet = MutableMagnet { k: key.verifying_key().to_bytes(), salt: salt.clone() };
+
+		// Bump `seq` past whatever is published now, unless it already points at `info_hash`.
+		let (seq, cas) = match self.get_mutable(magnet.k, salt.clone()).await {
+			Some(item) if pointer_infohash(item.v()) == Some(info_hash) => (item.seq().unwrap_or(1), None),
+			Some(item) => (item.seq().unwrap_or_default() + 1, item.seq()),
+			None => (1, None),
+		};
+
+		let item = Item::mutable(&key, salt.clone(), seq, pointer_value(info_hash));
+		self.put_item(item.clone(), cas).await?;
+		let task = self.every(REANNOUNCE_INTERVAL, move |dht| {
+			let item = item.clone();
+			async move {
+				if let Err(e) = dht.put_item(item, None).await {
+					warn!("republish error: {:?}", e);
+				}
+			}
+		});
+		if let Some(previous) = self.0.republishing.lock().unwrap().insert((magnet.k, salt), task) {
+			previous.abort();
 		}
 
-		let mut data = vec![0; len];
-		let n = rx.read_exact(&mut data).await.unwrap();
-		assert_eq!(n, len);
+		Ok(magnet.to_uri())
+	}
+
+	/// Walk towards `target` asking for infohash samples; samples are stored as they arrive.
+	pub async fn sample_infohashes(&self, target: [u8; 20], ipv6: bool) -> Vec<LookupNode> {
+		self.lookup_closest(target, LookupKind::SampleInfohashes, ipv6).await
+	}
 
-		if len == 1 {
-			info!("read 1 data byte ({}) from {}", data[0], host);
-			continue;
+	/// A random id in our identity's slice of the keyspace, or anywhere if we aren't one.
+	fn crawl_target(&self) -> [u8; 20] {
+		match self.0.identity {
+			Some((slot, count)) => id_in_slice(slot, count),
+			None => rand::random(),
 		}
+	}
 
-		match data[0..=1] {
-			[20, 0] => {
-				let ext = ExtensionHandshake::from_bytes(&data[2..len]).unwrap();
-				remote_extension_id = ext.m.ut_metadata;
-				if let Some(extension_id) = remote_extension_id {
-					dbg!(&ext);
-					metainfo.got_size(ext.metadata_size.unwrap()).await;
-					if let Some(piece) = metainfo.which_piece().await {
-						tx
-							.write_all(&MetadataMessage { msg_type: 0, piece, total_size: None }.to_bytes(extension_id))
-							.await
-							.unwrap();
-					} else {
-						return;
+	/// Look up peers for `info_hash` on every address family, without fetching metadata.
+	pub async fn find_peers(&self, info_hash: [u8; 20]) -> Vec<std::net::SocketAddr> {
+		let families = self.families();
+		let lookups = families.iter().map(|&ipv6| self.lookup(info_hash, LookupKind::GetPeers, ipv6));
+		let mut lookups = futures::stream::select_all(lookups);
+		let (mut peers, mut complete) = (HashSet::new(), 0);
+		while let Some(event) = lookups.next().await {
+			match event {
+				LookupEvent::Response { response, .. } => {
+					peers.extend(response.values.iter().flatten().filter_map(|peer| peer.addr()));
+				}
+				LookupEvent::Complete { .. } => {
+					complete += 1;
+					if complete == families.len() {
+						break;
 					}
 				}
 			}
+		}
+		peers.into_iter().collect()
+	}
 
-			[20, 2] => {
-				info!("got metadata message");
-				if metainfo.got_metadata_message(&data[2..len]).await {
-					return;
-				} else if let Some(piece) = metainfo.which_piece().await {
-					tx
-						.write_all(
-							&MetadataMessage { msg_type: 0, piece, total_size: None }
-								.to_bytes(remote_extension_id.unwrap()),
-						)
-						.await
-						.unwrap();
-				} else {
-					return;
-				};
+	/// Download the torrent `infohash` (hex) into `dir`, fetching its metadata from peers first.
+	///
+	/// Pieces already on disk are rechecked against their hashes and kept, so an interrupted
+	/// download picks up where it left off.
+	#[tracked::tracked]
+	pub fn download(
+		&self,
+		infohash: impl Into<String>,
+		dir: std::path::PathBuf,
+	) -> ProgressStream<()> {
+		let infohash = infohash.into();
+		let dht = self.clone();
+		Box::pin(async_stream::try_stream! {
+			let info_hash: [u8; 20] =
+				hex::decode(&infohash)?.try_into().map_err(|_| "infohash not 20 hex bytes")?;
+
+			let mut found = None;
+			let mut get_peers = dht.get_peers(&infohash);
+			while let Some(update) = get_peers.next().await {
+				match update? {
+					Progress::Progress { status } => yield Progress::Progress { status },
+					Progress::Complete { result, .. } => found = Some(result),
+				}
+			}
+			let GetPeersResult { metadata, mut peers, .. } = found.ok_or("lookup ended early")?;
+			let metadata = metadata.ok_or("no peer sent the metadata")?;
+			let info = InfoDict::from_bytes(&metadata)?;
+			info.validate()?;
+
+			yield progress!("checking existing data for {}", (info.name));
+			let download = dht.open_torrent(info_hash, metadata, info, dir).await?;
+			let port = dht.0.sock.local_addr()?.port();
+			// Peers can fetch what we have as we go.
+			dht.start_seeding(info_hash, port, false, download.picker.lock().unwrap().is_complete());
+
+			let mut connections: HashMap<std::net::SocketAddr, tokio::task::JoinHandle<()>> =
+				HashMap::new();
+			let mut last_lookup = std::time::Instant::now();
+			loop {
+				let failed = download.failed.lock().unwrap().clone();
+				if let Some(e) = failed {
+					connections.values().for_each(|connection| connection.abort());
+					Err(e)?;
+				}
+				let (have, total) = download.progress();
+				if have == total {
+					break;
+				}
+				for addr in peers.drain(..) {
+					if connections.get(&addr).is_none_or(|connection| connection.is_finished()) {
+						let (dht, download) = (dht.clone(), download.clone());
+						connections.insert(addr, tokio::spawn(async move {
+							dht.download_from_peer(addr, download).await;
+						}));
+					}
+				}
+				let active = connections.values().filter(|connection| !connection.is_finished()).count();
+				yield progress!("downloading {infohash}: {have}/{total} pieces, {active} peers");
+
+				if active == 0 && last_lookup.elapsed() >= PEER_LOOKUP_INTERVAL {
+					last_lookup = std::time::Instant::now();
+					peers = dht.find_peers(info_hash).await;
+				}
+				tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+			}
+
+			// Connections stay up to serve peers that still want pieces.
+			dht.start_seeding(info_hash, port, false, true);
+			yield complete!((), "downloaded {infohash} ({} pieces)", (download.progress().1));
+		})
+	}
+
+	/// Seed the torrent whose info dictionary is `metadata` from the data in `dir`: serve the
+	/// metadata and the pieces on disk to peers that connect for it, and announce it.
+	#[tracked::tracked]
+	pub async fn seed(
+		&self,
+		metadata: Vec<u8>,
+		dir: std::path::PathBuf,
+	) -> Result<[u8; 20], tracked::StringError> {
+		use sha1::{Digest, Sha1};
+		let info_hash: [u8; 20] = Sha1::digest(&metadata).into();
+		let info = InfoDict::from_bytes(&metadata)?;
+		info.validate()?;
+		self.open_torrent(info_hash, metadata, info, dir).await?;
+		self.start_seeding(info_hash, self.0.sock.local_addr()?.port(), false, true);
+		Ok(info_hash)
+	}
+
+	/// Set the priority of each of `info_hash`'s files, in torrent order: 0 skips a file, and
+	/// pieces of higher priority files are fetched first. Files default to
+	/// [`DEFAULT_PRIORITY`]. Applies at once if the torrent is downloading.
+	pub fn set_file_priorities(&self, info_hash: [u8; 20], priorities: Vec<u8>) {
+		if let Some(torrent) = self.0.torrents.lock().unwrap().get(&info_hash) {
+			torrent.picker.lock().unwrap().set_file_priorities(&priorities);
+		}
+		self.0.file_priorities.lock().unwrap().insert(info_hash, priorities);
+	}
+
+	/// Recheck a torrent's data in `dir`, and serve it to peers that connect for it.
+	async fn open_torrent(
+		&self,
+		info_hash: [u8; 20],
+		metadata: Vec<u8>,
+		info: InfoDict,
+		dir: std::path::PathBuf,
+	) -> Result<std::sync::Arc<Download>, String> {
+		let open = move || Download::open(info_hash, metadata, info, &dir);
+		let download = tokio::task::spawn_blocking(open).await.map_err(|e| e.to_string())?;
+		let download = std::sync::Arc::new(download.map_err(|e| e.to_string())?);
+		if let Some(priorities) = self.0.file_priorities.lock().unwrap().get(&info_hash) {
+			download.picker.lock().unwrap().set_file_priorities(priorities);
+		}
+		self.0.torrents.lock().unwrap().insert(info_hash, download.clone());
+		Ok(download)
+	}
+
+	/// Crawl the DHT with BEP 51 `sample_infohashes`, storing every infohash we're shown.
+	///
+	/// Each node is asked with a random target, no more often than its `interval` allows, and
+	/// the nodes it returns are crawled in turn. When we run out of nodes to ask, a `find_node`
+	/// lookup towards a random target finds more. Identities keep their targets in their slice.
+	pub fn crawl(&self) -> ProgressStream<()> {
+		let dht = self.clone();
+		Box::pin(async_stream::try_stream! {
+			use std::sync::atomic::Ordering;
+
+			let dht = &dht;
+			let mut crawler = Crawler::default();
+			let mut rate = RatePerMinute::default();
+			let mut in_flight = futures::stream::FuturesUnordered::new();
+			let mut seen = dht.0.new_infohashes.load(Ordering::Relaxed);
+			let mut last_progress = std::time::Instant::now();
+
+			for ipv6 in dht.families() {
+				for node in dht.routing_table(ipv6).nodes() {
+					crawler.add(node.addr);
+				}
+			}
+
+			loop {
+				let room = if dht.congested() { 0 } else { CRAWL_IN_FLIGHT.saturating_sub(in_flight.len()) };
+				for addr in crawler.due(room) {
+					let target = dht.crawl_target();
+					in_flight.push(async move {
+						let id = dht.self_id();
+						let query = |t: &[u8], ro| SampleInfohashesQuery { id, target }.into_bytes(t, ro);
+						(addr, dht.request(addr, query).await)
+					});
+				}
+
+				if in_flight.is_empty() {
+					for ipv6 in dht.families().into_iter().filter(|_| !dht.congested()) {
+						for node in dht.find_node(dht.crawl_target(), ipv6).await {
+							crawler.add(node.addr);
+						}
+					}
+					tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+				} else if let Ok(Some((addr, response))) =
+					tokio::time::timeout(std::time::Duration::from_secs(1), in_flight.next()).await
+				{
+					match response {
+						Ok(response) => crawler.on_response(addr, &response, addr.is_ipv6()),
+						Err(RequestError::Krpc(error)) => crawler.on_error(addr, &error),
+						Err(_) => crawler.on_failure(addr),
+					}
+				}
+
+				let total = dht.0.new_infohashes.load(Ordering::Relaxed);
+				rate.record(total - seen);
+				seen = total;
+
+				if last_progress.elapsed() >= std::time::Duration::from_secs(1) {
+					last_progress = std::time::Instant::now();
+					yield progress!(
+						"crawling {} nodes, {} with samples (num {}), {} in flight; {} new infohashes/min",
+						(crawler.len()),
+						(crawler.supported()),
+						(crawler.total_num()),
+						(in_flight.len()),
+						(rate.per_minute())
+					);
+				}
 			}
+		})
+	}
 
+	/// Connect to a peer and exchange handshakes for `info_hash`.
+	async fn connect_peer(
+		&self,
+		addr: std::net::SocketAddr,
+		info_hash: [u8; 20],
+	) -> Option<(PeerReader, PeerWriter)> {
+		let tout = std::time::Duration::from_secs(5);
+		use tokio::time::timeout;
+		info!("connecting {:?}", addr);
+		let Ok(Ok(s)) = timeout(tout, self.0.transport.connect(addr)).await else {
+			info!("failed {:?}", addr);
+			return None;
+		};
+		info!("CONNECTED {:?}", addr);
+		let (rx, mut tx) = tokio::io::split(s);
+		let mut rx = tokio::io::BufReader::new(rx);
+
+		let handshake = Handshake { info_hash, peer_id: self.self_id() };
+		tx.write_all(&handshake.to_bytes()).await.ok()?;
+
+		let mut handshake = [0; Handshake::LEN];
+		let Ok(Ok(_)) = timeout(tout, rx.read_exact(&mut handshake)).await else {
+			info!("handshake failed {:?}", addr);
+			return None;
+		};
+		match Handshake::from_bytes(&handshake) {
+			Some(handshake) if handshake.info_hash == info_hash => Some((rx, tx)),
 			_ => {
-				// info!(
-				// 	"read {} data bytes (type {}) from {}: {:?}",
-				// 	n,
-				// 	data[0],
-				// 	host,
-				// 	String::from_utf8_lossy(&data)
-				// )
+				info!("bad handshake from {:?}", addr);
+				None
+			}
+		}
+	}
+
+	/// Fetch what we can of `metainfo` from a peer, one piece at a time.
+	async fn run_peer(&self, host: std::net::SocketAddr, metainfo: MetaInfo) {
+		if metainfo.is_banned(host) {
+			return;
+		}
+		let Some((mut rx, mut tx)) = self.connect_peer(host, metainfo.infohash()).await else {
+			return;
+		};
+		let (messages_tx, mut messages) = tokio::sync::mpsc::channel(16);
+		let reader = tokio::spawn(async move {
+			while let Ok(message) = PeerMessage::read(&mut rx).await {
+				if messages_tx.send(message).await.is_err() {
+					break;
+				}
 			}
+		});
+
+		let result = self.exchange_metadata(host, &mut messages, &mut tx, &metainfo).await;
+		reader.abort();
+		metainfo.release(host).await;
+		match result {
+			Ok(true) if self.0.persistent => {
+				if let Err(e) = metainfo.save().await {
+					warn!("could not save metadata from {}: {:?}", host, e);
+				}
+			}
+			Ok(_) => {}
+			Err(e) => info!("dropping {}: {}", host, e),
+		}
+	}
+
+	/// The ut_metadata side of [`Dht::run_peer`]: whether the metadata was completed by this
+	/// peer, or why it was dropped.
+	async fn exchange_metadata(
+		&self,
+		host: std::net::SocketAddr,
+		messages: &mut tokio::sync::mpsc::Receiver<PeerMessage>,
+		tx: &mut PeerWriter,
+		metainfo: &MetaInfo,
+	) -> Result<bool, String> {
+		let handshake = ExtensionHandshake::default().to_message();
+		tx.write_all(&handshake.to_bytes()).await.map_err(|e| e.to_string())?;
+
+		let mut peer = PeerState::default();
+		let mut pending = None;
+		let mut last_message = tokio::time::Instant::now();
+		let mut tick = tokio::time::interval(std::time::Duration::from_secs(1));
+		loop {
+			if metainfo.is_banned(host) {
+				return Err("banned".into());
+			}
+			if metainfo.is_complete().await {
+				return Ok(false);
+			}
+			match pending {
+				Some(piece) if !metainfo.waiting_on(host, piece).await => {
+					return Err(format!("metadata piece {piece} timed out"));
+				}
+				Some(_) => {}
+				None => {
+					if let Some(extension_id) = peer.ut_metadata {
+						if let Some(piece) = metainfo.which_piece(host).await {
+							let request = MetadataMessage { msg_type: 0, piece, total_size: None };
+							tx.write_all(&request.to_bytes(extension_id)).await.map_err(|e| e.to_string())?;
+							pending = Some(piece);
+						}
+					}
+				}
+			}
+
+			let message = tokio::select! {
+				message = messages.recv() => message.ok_or("connection closed")?,
+				_ = tick.tick() => {
+					if last_message.elapsed() >= PEER_IDLE_TIMEOUT {
+						return Err("idle".into());
+					}
+					continue;
+				}
+			};
+			last_message = tokio::time::Instant::now();
+			peer.received(&message).map_err(|e| e.to_string())?;
+
+			match message {
+				PeerMessage::Extended { id: 0, .. } => match (peer.ut_metadata, peer.metadata_size) {
+					(Some(_), Some(size)) => metainfo.got_size(host, size).await?,
+					_ => return Ok(false),
+				},
+				PeerMessage::Extended { id: UT_METADATA_ID, payload } => {
+					pending = None;
+					if metainfo.got_metadata_message(host, &payload).await? {
+						return Ok(true);
+					}
+				}
+				_ => {}
+			}
+		}
+	}
+
+	/// Fetch blocks of `download` from a peer, which may fetch blocks from us in turn.
+	async fn download_from_peer(
+		&self,
+		addr: std::net::SocketAddr,
+		download: std::sync::Arc<Download>,
+	) {
+		let Some((rx, tx)) = self.connect_peer(addr, download.info_hash).await else { return };
+		self.run_torrent_peer(addr, rx, tx, download).await;
+	}
+
+	/// Take a peer connection made to us, if it's for a torrent we have.
+	async fn accept_peer(&self, stream: Box<dyn PeerStream>, addr: std::net::SocketAddr) {
+		let (rx, mut tx) = tokio::io::split(stream);
+		let mut rx = tokio::io::BufReader::new(rx);
+
+		let mut handshake = [0; Handshake::LEN];
+		let tout = std::time::Duration::from_secs(5);
+		let Ok(Ok(_)) = tokio::time::timeout(tout, rx.read_exact(&mut handshake)).await else {
+			return;
+		};
+		let Some(Handshake { info_hash, .. }) = Handshake::from_bytes(&handshake) else {
+			return info!("bad handshake from {:?}", addr);
+		};
+		let Some(download) = self.0.torrents.lock().unwrap().get(&info_hash).cloned() else {
+			return info!("{:?} asked for {}, which we don't have", addr, hex::encode(info_hash));
+		};
+
+		let handshake = Handshake { info_hash, peer_id: self.self_id() };
+		if tx.write_all(&handshake.to_bytes()).await.is_err() {
+			return;
+		}
+		self.run_torrent_peer(addr, rx, tx, download).await;
+	}
+
+	/// Exchange pieces of `download` with a peer once handshakes are done: fetch the blocks we
+	/// lack, and serve blocks and metadata while the choker gives the peer an upload slot.
+	async fn run_torrent_peer(
+		&self,
+		addr: std::net::SocketAddr,
+		mut rx: PeerReader,
+		mut tx: PeerWriter,
+		download: std::sync::Arc<Download>,
+	) {
+		use std::sync::atomic::Ordering;
+
+		let (messages_tx, mut messages) = tokio::sync::mpsc::channel(16);
+		let reader = tokio::spawn(async move {
+			while let Ok(message) = PeerMessage::read(&mut rx).await {
+				if messages_tx.send(message).await.is_err() {
+					break;
+				}
+			}
+		});
+		let (id, slot) = download.choker.lock().unwrap().add();
+
+		let mut peer = PeerState::new(download.info.num_pieces() as u32);
+		let mut outstanding = Vec::new();
+		let mut uploads = std::collections::VecDeque::new();
+		let mut last_message = tokio::time::Instant::now();
+		let mut tick = tokio::time::interval(std::time::Duration::from_secs(1));
+		let mut announced = download.picker.lock().unwrap().bitfield();
+		let mut send = Vec::new();
+		if announced.count() > 0 {
+			send.push(PeerMessage::Bitfield(announced.as_bytes().to_vec()));
+		}
+		let extensions =
+			ExtensionHandshake { metadata_size: Some(download.metadata.len()), ..Default::default() };
+		send.push(extensions.to_message());
+
+		'connection: loop {
+			let complete = download.picker.lock().unwrap().is_complete();
+			if complete == peer.am_interested {
+				send.push(if complete { PeerMessage::NotInterested } else { PeerMessage::Interested });
+			}
+			if slot.unchoked.load(Ordering::Relaxed) == peer.am_choking {
+				send.push(if peer.am_choking { PeerMessage::Unchoke } else { PeerMessage::Choke });
+			}
+			for message in send.drain(..) {
+				if message == PeerMessage::Choke {
+					uploads.clear();
+				}
+				peer.sending(&message);
+				if tx.write_all(&message.to_bytes()).await.is_err() {
+					break 'connection;
+				}
+			}
+
+			let message = tokio::select! {
+				biased;
+				message = messages.recv() => match message {
+					Some(message) => message,
+					None => break,
+				},
+				_ = tick.tick() => {
+					// Two seeds have nothing for each other.
+					let seed = peer.have.count() == download.info.num_pieces();
+					if (complete && seed) || last_message.elapsed() >= PEER_IDLE_TIMEOUT {
+						break;
+					}
+					let have = download.picker.lock().unwrap().bitfield();
+					for piece in 0..download.info.num_pieces() as u32 {
+						if have.has(piece) && !announced.has(piece) {
+							send.push(PeerMessage::Have { piece });
+						}
+					}
+					announced = have;
+					send.extend(Self::top_up(&download, &peer, &mut outstanding));
+					continue;
+				}
+				_ = std::future::ready(()), if !uploads.is_empty() => {
+					let (piece, offset, length) = uploads.pop_front().unwrap();
+					let (download, start) = (download.clone(), download.info.piece_offset(piece as usize));
+					let read = move || download.storage.read(start + offset as u64, length as usize);
+					let data = match tokio::task::spawn_blocking(read).await {
+						Ok(Ok(data)) => data,
+						Ok(Err(e)) => {
+							warn!("could not read piece {piece}: {e}");
+							break;
+						}
+						Err(_) => break,
+					};
+					slot.uploaded.fetch_add(data.len() as u64, Ordering::Relaxed);
+					send.push(PeerMessage::Piece { piece, offset, data });
+					continue;
+				}
+			};
+			last_message = tokio::time::Instant::now();
+
+			let had = match message {
+				PeerMessage::Have { piece } => peer.have.has(piece),
+				_ => false,
+			};
+			if let Err(e) = peer.received(&message) {
+				info!("dropping {}: {}", addr, e);
+				break;
+			}
+			match message {
+				PeerMessage::Have { piece } if !had => download.picker.lock().unwrap().peer_has(piece),
+				PeerMessage::Bitfield(_) => download.picker.lock().unwrap().peer_bitfield(&peer.have),
+				PeerMessage::Choke => {
+					let mut picker = download.picker.lock().unwrap();
+					for (piece, offset, _) in outstanding.drain(..) {
+						picker.abandon(piece, offset);
+					}
+				}
+				PeerMessage::Piece { piece, offset, data } => {
+					let Some(i) = outstanding.iter().position(|&(p, o, _)| (p, o) == (piece, offset)) else {
+						continue;
+					};
+					outstanding.swap_remove(i);
+					slot.downloaded.fetch_add(data.len() as u64, Ordering::Relaxed);
+					let complete = download.picker.lock().unwrap().received(piece, offset, &data);
+					if let Some(data) = complete {
+						let download = download.clone();
+						tokio::spawn(async move {
+							let storing = download.clone();
+							if let Err(e) = tokio::task::spawn_blocking(move || storing.store(piece, data)).await {
+								// Fetch the piece again rather than leave it half done.
+								warn!("storing piece {piece} failed: {e}");
+								download.picker.lock().unwrap().finished(piece, false);
+							}
+						});
+					}
+				}
+				PeerMessage::Interested => {
+					slot.interested.store(true, Ordering::Relaxed);
+					download.choker.lock().unwrap().interested(id);
+				}
+				PeerMessage::NotInterested => slot.interested.store(false, Ordering::Relaxed),
+				PeerMessage::Request { piece, offset, length } => {
+					let fits = || offset as u64 + length as u64 <= download.info.piece_len(piece as usize) as u64;
+					if uploads.len() >= MAX_QUEUED_UPLOADS {
+						info!("dropping {:?}: more than {MAX_QUEUED_UPLOADS} requests queued", addr);
+						break;
+					}
+					if !peer.am_choking && length <= BLOCK_SIZE && download.has(piece) && fits() {
+						uploads.push_back((piece, offset, length));
+					}
+				}
+				PeerMessage::Cancel { piece, offset, length } => {
+					uploads.retain(|&request| request != (piece, offset, length));
+				}
+				PeerMessage::Extended { id: UT_METADATA_ID, payload } => {
+					let Some(extension_id) = peer.ut_metadata else { continue };
+					let Ok(request) = MetadataMessage::from_bytes(&payload) else { continue };
+					if request.msg_type == 0 {
+						send.push(MetadataMessage::reply(&download.metadata, request.piece, extension_id));
+					}
+				}
+				_ => {}
+			}
+			send.extend(Self::top_up(&download, &peer, &mut outstanding));
+		}
+
+		reader.abort();
+		download.choker.lock().unwrap().remove(id);
+		let mut picker = download.picker.lock().unwrap();
+		for (piece, offset, _) in outstanding {
+			picker.abandon(piece, offset);
+		}
+		picker.peer_gone(&peer.have);
+	}
+
+	/// Cancels for requests another peer has answered in endgame, then requests to send a
+	/// peer so that up to [`REQUEST_PIPELINE`] are outstanding.
+	fn top_up(
+		download: &Download,
+		peer: &PeerState,
+		outstanding: &mut Vec<(u32, u32, u32)>,
+	) -> Vec<PeerMessage> {
+		let mut requests = Vec::new();
+		let mut picker = download.picker.lock().unwrap();
+		outstanding.retain(|&(piece, offset, length)| {
+			let done = picker.is_done(piece, offset);
+			if done {
+				requests.push(PeerMessage::Cancel { piece, offset, length });
+			}
+			!done
+		});
+		while peer.can_request() && outstanding.len() < REQUEST_PIPELINE {
+			let Some((piece, offset, length)) = picker.next_request(&peer.have, outstanding) else {
+				break;
+			};
+			outstanding.push((piece, offset, length));
+			requests.push(PeerMessage::Request { piece, offset, length });
 		}
+		requests
 	}
 }
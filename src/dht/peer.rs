@@ -0,0 +1,142 @@
+use super::{ExtensionHandshake, PeerMessage};
+use std::io;
+
+/// The pieces a peer has, high bit of the first byte first.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Bitfield(Vec<u8>);
+
+impl Bitfield {
+	pub fn from_bytes(bytes: Vec<u8>) -> Self {
+		Self(bytes)
+	}
+
+	pub fn as_bytes(&self) -> &[u8] {
+		&self.0
+	}
+
+	pub fn has(&self, piece: u32) -> bool {
+		let (byte, mask) = (piece as usize / 8, 0x80 >> (piece % 8));
+		self.0.get(byte).is_some_and(|b| b & mask != 0)
+	}
+
+	/// Mark `piece` as had, growing the bitfield if needed.
+	pub fn set(&mut self, piece: u32) {
+		let (byte, mask) = (piece as usize / 8, 0x80 >> (piece % 8));
+		if byte >= self.0.len() {
+			self.0.resize(byte + 1, 0);
+		}
+		self.0[byte] |= mask;
+	}
+
+	/// How many pieces are set.
+	pub fn count(&self) -> usize {
+		self.0.iter().map(|b| b.count_ones() as usize).sum()
+	}
+}
+
+/// What we know about one peer connection: choke and interest in both directions, which
+/// pieces the peer has, and the extensions it supports.
+///
+/// Connections start out choked and not interested on both sides (BEP 3).
+#[derive(Debug)]
+pub struct PeerState {
+	/// We won't answer the peer's requests.
+	pub am_choking: bool,
+	/// We want pieces the peer has.
+	pub am_interested: bool,
+	/// The peer won't answer our requests.
+	pub peer_choking: bool,
+	/// The peer wants pieces we have.
+	pub peer_interested: bool,
+	pub have: Bitfield,
+	/// BEP 9: the id the peer wants its ut_metadata messages sent with.
+	pub ut_metadata: Option<u8>,
+	/// BEP 9: size of the info dictionary, from the peer's extension handshake.
+	pub metadata_size: Option<usize>,
+	/// Whether anything but a bitfield has arrived; a bitfield may only come first.
+	started: bool,
+	/// Pieces in the torrent, which haves and bitfields are checked against. Unknown while
+	/// fetching metadata, when they're ignored.
+	num_pieces: Option<u32>,
+}
+
+impl Default for PeerState {
+	fn default() -> Self {
+		Self {
+			am_choking: true,
+			am_interested: false,
+			peer_choking: true,
+			peer_interested: false,
+			have: Bitfield::default(),
+			ut_metadata: None,
+			metadata_size: None,
+			started: false,
+			num_pieces: None,
+		}
+	}
+}
+
+impl PeerState {
+	/// A connection for a torrent of `num_pieces` pieces.
+	pub fn new(num_pieces: u32) -> Self {
+		Self { num_pieces: Some(num_pieces), ..Default::default() }
+	}
+
+	/// Update our view of the peer for a message it sent. Errors are protocol violations, after
+	/// which the connection should be dropped.
+	pub fn received(&mut self, message: &PeerMessage) -> io::Result<()> {
+		let started = std::mem::replace(&mut self.started, true);
+		match message {
+			PeerMessage::Choke => self.peer_choking = true,
+			PeerMessage::Unchoke => self.peer_choking = false,
+			PeerMessage::Interested => self.peer_interested = true,
+			PeerMessage::NotInterested => self.peer_interested = false,
+			PeerMessage::Have { piece } => match self.num_pieces {
+				Some(num_pieces) if *piece >= num_pieces => {
+					return Err(io::Error::new(io::ErrorKind::InvalidData, "have for no such piece"));
+				}
+				Some(_) => self.have.set(*piece),
+				None => {}
+			},
+			PeerMessage::Bitfield(_) if started => {
+				return Err(io::Error::new(io::ErrorKind::InvalidData, "bitfield after other messages"));
+			}
+			PeerMessage::Bitfield(bitfield) => {
+				let Some(num_pieces) = self.num_pieces else { return Ok(()) };
+				let have = Bitfield::from_bytes(bitfield.clone());
+				// The right length, with no bits past the last piece.
+				if bitfield.len() != (num_pieces as usize).div_ceil(8)
+					|| (num_pieces..bitfield.len() as u32 * 8).any(|piece| have.has(piece))
+				{
+					return Err(io::Error::new(io::ErrorKind::InvalidData, "bitfield doesn't fit the torrent"));
+				}
+				self.have = have;
+			}
+			PeerMessage::Extended { id: 0, payload } => {
+				let handshake = ExtensionHandshake::from_bytes(payload)
+					.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+				self.ut_metadata = handshake.m.ut_metadata;
+				self.metadata_size = handshake.metadata_size;
+			}
+			PeerMessage::KeepAlive => self.started = started,
+			_ => {}
+		}
+		Ok(())
+	}
+
+	/// Update our side of the connection for a message we're sending.
+	pub fn sending(&mut self, message: &PeerMessage) {
+		match message {
+			PeerMessage::Choke => self.am_choking = true,
+			PeerMessage::Unchoke => self.am_choking = false,
+			PeerMessage::Interested => self.am_interested = true,
+			PeerMessage::NotInterested => self.am_interested = false,
+			_ => {}
+		}
+	}
+
+	/// Whether we may send the peer requests.
+	pub fn can_request(&self) -> bool {
+		self.am_interested && !self.peer_choking
+	}
+}
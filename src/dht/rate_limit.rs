@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+// tokio's clock, so the limiter keeps working when tests pause time.
+use tokio::time::Instant;
+
+/// Outgoing packets per second we allow, overall and towards one host or network.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimits {
+	pub global: f64,
+	pub per_ip: f64,
+	/// Per IPv4 /24, or IPv6 /48.
+	pub per_subnet: f64,
+}
+
+impl Default for RateLimits {
+	fn default() -> Self {
+		Self { global: 500.0, per_ip: 5.0, per_subnet: 20.0 }
+	}
+}
+
+impl RateLimits {
+	/// Every rate must be a positive number of packets per second.
+	pub fn validate(&self) -> Result<(), String> {
+		for (name, rate) in
+			[("global", self.global), ("per-IP", self.per_ip), ("per-subnet", self.per_subnet)]
+		{
+			if !(rate.is_finite() && rate > 0.0) {
+				return Err(format!("{name} rate limit must be positive, not {rate}"));
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Refills at `rate` tokens a second, holding at most one second's worth.
+#[derive(Debug)]
+struct TokenBucket {
+	rate: f64,
+	tokens: f64,
+	last: Instant,
+}
+
+impl TokenBucket {
+	fn new(rate: f64, now: Instant) -> Self {
+		Self { rate, tokens: rate.max(1.0), last: now }
+	}
+
+	fn refill(&mut self, now: Instant) {
+		let elapsed = now.duration_since(self.last).as_secs_f64();
+		self.tokens = (self.tokens + elapsed * self.rate).min(self.rate.max(1.0));
+		self.last = now;
+	}
+
+	/// How long until a token is available.
+	fn wait(&self) -> Duration {
+		if self.tokens >= 1.0 {
+			Duration::ZERO
+		} else {
+			Duration::from_secs_f64((1.0 - self.tokens) / self.rate)
+		}
+	}
+
+	fn is_full(&self) -> bool {
+		self.tokens >= self.rate.max(1.0)
+	}
+}
+
+/// Token buckets limiting the packets we send, globally, per IP and per subnet.
+#[derive(Debug)]
+pub struct RateLimiter {
+	limits: RateLimits,
+	global: TokenBucket,
+	per_ip: HashMap<IpAddr, TokenBucket>,
+	per_subnet: HashMap<IpAddr, TokenBucket>,
+	pub throttled: u64,
+	pub dropped: u64,
+}
+
+fn subnet(ip: IpAddr) -> IpAddr {
+	match ip {
+		IpAddr::V4(ip) => {
+			let [a, b, c, _] = ip.octets();
+			IpAddr::from([a, b, c, 0])
+		}
+		IpAddr::V6(ip) => {
+			let s = ip.segments();
+			IpAddr::from([s[0], s[1], s[2], 0, 0, 0, 0, 0])
+		}
+	}
+}
+
+impl RateLimiter {
+	pub fn new(limits: RateLimits) -> Self {
+		Self {
+			limits,
+			global: TokenBucket::new(limits.global, Instant::now()),
+			per_ip: HashMap::new(),
+			per_subnet: HashMap::new(),
+			throttled: 0,
+			dropped: 0,
+		}
+	}
+
+	/// Take a token for a packet to `addr` if every bucket has one; otherwise return how
+	/// long to wait before trying again.
+	pub fn acquire(&mut self, addr: &SocketAddr) -> Result<(), Duration> {
+		let now = Instant::now();
+		let ip = addr.ip();
+		let limits = self.limits;
+		let per_ip = self.per_ip.entry(ip).or_insert_with(|| TokenBucket::new(limits.per_ip, now));
+		let per_subnet =
+			self.per_subnet.entry(subnet(ip)).or_insert_with(|| TokenBucket::new(limits.per_subnet, now));
+
+		let mut buckets = [&mut self.global, per_ip, per_subnet];
+		let mut wait = Duration::ZERO;
+		for bucket in buckets.iter_mut() {
+			bucket.refill(now);
+			wait = wait.max(bucket.wait());
+		}
+		if wait > Duration::ZERO {
+			return Err(wait);
+		}
+		for bucket in buckets {
+			bucket.tokens -= 1.0;
+		}
+		Ok(())
+	}
+
+	/// Forget buckets that have refilled, so idle hosts don't use memory.
+	pub fn prune(&mut self) {
+		let now = Instant::now();
+		for buckets in [&mut self.per_ip, &mut self.per_subnet] {
+			buckets.retain(|_, bucket| {
+				bucket.refill(now);
+				!bucket.is_full()
+			});
+		}
+	}
+
+	/// Whether we're sending as fast as the global limit allows; lookups should hold back.
+	/// Busy per-IP or per-subnet buckets only slow down packets to those hosts.
+	pub fn congested(&mut self) -> bool {
+		self.global.refill(Instant::now());
+		self.global.tokens < 1.0
+	}
+}
@@ -0,0 +1,518 @@
+use super::verify_node_id;
+use rand::prelude::*;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Bucket size, per BEP 5.
+pub const K: usize = 8;
+
+/// A node that hasn't been heard from for this long is questionable.
+const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// A bucket that hasn't changed for this long should be refreshed.
+const REFRESH_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// How long a questionable node has to answer our ping before it is evicted.
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Consecutive failed queries after which a node is bad.
+const MAX_FAILURES: u32 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeStatus {
+	Good,
+	Questionable,
+	Bad,
+}
+
+/// What to do with nodes whose ids don't match their IP per BEP 42.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NodeIdPolicy {
+	/// Treat them like any other node.
+	Ignore,
+	/// Keep them only until a compliant node wants their slot.
+	#[default]
+	Prefer,
+	/// Never store them.
+	Require,
+}
+
+impl std::str::FromStr for NodeIdPolicy {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"ignore" => Ok(Self::Ignore),
+			"prefer" => Ok(Self::Prefer),
+			"require" => Ok(Self::Require),
+			_ => Err(format!("unknown node id policy {s:?}; expected ignore, prefer or require")),
+		}
+	}
+}
+
+impl std::fmt::Display for NodeIdPolicy {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(match self {
+			Self::Ignore => "ignore",
+			Self::Prefer => "prefer",
+			Self::Require => "require",
+		})
+	}
+}
+
+/// How we heard from a node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Heard {
+	/// It answered one of our queries.
+	Response,
+	/// It sent us a query.
+	Query,
+}
+
+#[derive(Clone, Debug)]
+pub struct RoutingNode {
+	pub id: [u8; 20],
+	pub addr: SocketAddr,
+	pub last_response: Option<Instant>,
+	pub last_query: Option<Instant>,
+	pub failures: u32,
+	/// Queries we've sent this node, and how many of those went unanswered.
+	pub queries: u32,
+	pub timeouts: u32,
+	/// Queries this node answered with a KRPC error.
+	pub errors: u32,
+	/// Smoothed round-trip time of our queries.
+	pub rtt: Option<Duration>,
+	/// Whether the id matches the address per BEP 42.
+	pub compliant: bool,
+}
+
+impl RoutingNode {
+	pub fn status(&self, now: Instant) -> NodeStatus {
+		if self.failures >= MAX_FAILURES {
+			return NodeStatus::Bad;
+		}
+		let recent = |t: Option<Instant>| t.is_some_and(|t| now.duration_since(t) < QUESTIONABLE_AFTER);
+		if recent(self.last_response) || (self.last_response.is_some() && recent(self.last_query)) {
+			NodeStatus::Good
+		} else {
+			NodeStatus::Questionable
+		}
+	}
+
+	fn new(id: [u8; 20], addr: SocketAddr) -> Self {
+		Self {
+			id,
+			addr,
+			last_response: None,
+			last_query: None,
+			failures: 0,
+			queries: 0,
+			timeouts: 0,
+			errors: 0,
+			rtt: None,
+			compliant: verify_node_id(&addr.ip(), &id),
+		}
+	}
+
+	/// Fraction of our queries this node has answered.
+	pub fn reliability(&self) -> Option<f32> {
+		(self.queries > 0).then(|| 1.0 - self.timeouts as f32 / self.queries as f32)
+	}
+
+	fn last_seen(&self) -> Option<Instant> {
+		self.last_response.max(self.last_query)
+	}
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BucketStats {
+	pub good: usize,
+	pub questionable: usize,
+	pub bad: usize,
+	/// Nodes whose ids fail BEP 42 verification.
+	pub forged: usize,
+}
+
+/// A full bucket's questionable node that we've pinged, and the node that
+/// replaces it if the ping goes unanswered.
+#[derive(Debug)]
+struct PendingEviction {
+	pinged: [u8; 20],
+	candidate: RoutingNode,
+	since: Instant,
+}
+
+#[derive(Debug)]
+struct Bucket {
+	nodes: Vec<RoutingNode>,
+	last_changed: Instant,
+	pending: Option<PendingEviction>,
+}
+
+/// Kademlia routing table keyed on our own node id.
+///
+/// Bucket `i` holds nodes whose XOR distance from us has `i` leading zero bits,
+/// so the buckets near us are the ones that stay sparse.
+#[derive(Debug)]
+pub struct RoutingTable {
+	self_id: [u8; 20],
+	policy: NodeIdPolicy,
+	buckets: Vec<Bucket>,
+}
+
+pub fn xor_distance(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+	let mut out = [0; 20];
+	for (o, (a, b)) in out.iter_mut().zip(a.iter().zip(b)) {
+		*o = a ^ b;
+	}
+	out
+}
+
+fn leading_zeros(d: &[u8; 20]) -> usize {
+	d.iter().position(|b| *b != 0).map_or(160, |i| i * 8 + d[i].leading_zeros() as usize)
+}
+
+impl RoutingTable {
+	pub fn new(self_id: [u8; 20], policy: NodeIdPolicy) -> Self {
+		let now = Instant::now();
+		Self {
+			self_id,
+			policy,
+			buckets: (0..160)
+				.map(|_| Bucket { nodes: Vec::new(), last_changed: now, pending: None })
+				.collect(),
+		}
+	}
+
+	pub fn self_id(&self) -> [u8; 20] {
+		self.self_id
+	}
+
+	pub fn policy(&self) -> NodeIdPolicy {
+		self.policy
+	}
+
+	/// Re-key the table on a new node id, keeping whichever nodes still fit.
+	pub fn set_self_id(&mut self, self_id: [u8; 20]) {
+		let old = std::mem::replace(self, Self::new(self_id, self.policy));
+		for node in old.buckets.into_iter().flat_map(|b| b.nodes) {
+			let Some(index) = self.bucket_index(&node.id) else { continue };
+			let bucket = &mut self.buckets[index];
+			if bucket.nodes.len() < K {
+				bucket.nodes.push(node);
+			}
+		}
+	}
+
+	fn bucket_index(&self, id: &[u8; 20]) -> Option<usize> {
+		match leading_zeros(&xor_distance(&self.self_id, id)) {
+			160 => None,
+			i => Some(i),
+		}
+	}
+
+	/// Record that we heard from a node, adding it if there is room.
+	///
+	/// If its bucket is full of nodes that might still be alive, the least
+	/// recently seen questionable node is returned; the caller should ping it,
+	/// and if it doesn't answer in time [`Self::expire_pending`] replaces it
+	/// with the new node.
+	pub fn heard_from(&mut self, id: [u8; 20], addr: SocketAddr, heard: Heard) -> Option<RoutingNode> {
+		let now = Instant::now();
+		let index = self.bucket_index(&id)?;
+		let policy = self.policy;
+		let bucket = &mut self.buckets[index];
+
+		if let Some(node) = bucket.nodes.iter_mut().find(|n| n.id == id) {
+			match heard {
+				Heard::Response => {
+					node.last_response = Some(now);
+					node.failures = 0;
+					if bucket.pending.as_ref().is_some_and(|p| p.pinged == id) {
+						bucket.pending = None;
+					}
+				}
+				Heard::Query => node.last_query = Some(now),
+			}
+			bucket.last_changed = now;
+			return None;
+		}
+
+		let node = RoutingNode {
+			last_response: (heard == Heard::Response).then_some(now),
+			last_query: (heard == Heard::Query).then_some(now),
+			..RoutingNode::new(id, addr)
+		};
+
+		if !node.compliant && policy == NodeIdPolicy::Require {
+			return None;
+		}
+
+		if bucket.nodes.len() < K {
+			bucket.nodes.push(node);
+			bucket.last_changed = now;
+			return None;
+		}
+
+		if let Some(bad) = bucket.nodes.iter_mut().find(|n| n.status(now) == NodeStatus::Bad) {
+			*bad = node;
+			bucket.last_changed = now;
+			return None;
+		}
+
+		if policy == NodeIdPolicy::Prefer {
+			if !node.compliant {
+				return None;
+			}
+			if let Some(forged) = bucket.nodes.iter_mut().find(|n| !n.compliant) {
+				*forged = node;
+				bucket.last_changed = now;
+				return None;
+			}
+		}
+
+		if bucket.pending.is_some() {
+			return None;
+		}
+
+		let oldest = bucket
+			.nodes
+			.iter()
+			.filter(|n| n.status(now) == NodeStatus::Questionable)
+			.min_by_key(|n| n.last_seen())?
+			.clone();
+
+		bucket.pending = Some(PendingEviction { pinged: oldest.id, candidate: node, since: now });
+
+		Some(oldest)
+	}
+
+	/// Add a node loaded from the database, without displacing anything.
+	pub fn load(&mut self, id: [u8; 20], addr: SocketAddr, last_response: Option<Instant>) {
+		let Some(index) = self.bucket_index(&id) else { return };
+		let node = RoutingNode { last_response, ..RoutingNode::new(id, addr) };
+		if !node.compliant && self.policy == NodeIdPolicy::Require {
+			return;
+		}
+		let bucket = &mut self.buckets[index];
+		if bucket.nodes.len() < K && !bucket.nodes.iter().any(|n| n.id == id) {
+			bucket.nodes.push(node);
+		}
+	}
+
+	fn find_by_addr(&mut self, addr: &SocketAddr) -> Option<&mut RoutingNode> {
+		self.buckets.iter_mut().flat_map(|b| b.nodes.iter_mut()).find(|n| n.addr == *addr)
+	}
+
+	/// Record that we sent a query to the node at `addr`.
+	pub fn queried(&mut self, addr: &SocketAddr) {
+		if let Some(node) = self.find_by_addr(addr) {
+			node.queries += 1;
+		}
+	}
+
+	/// Record how long the node at `addr` took to answer one of our queries.
+	pub fn responded(&mut self, addr: &SocketAddr, rtt: Duration) {
+		if let Some(node) = self.find_by_addr(addr) {
+			node.rtt = Some(node.rtt.map_or(rtt, |smoothed| (smoothed * 7 + rtt) / 8));
+		}
+	}
+
+	/// Record that the node at `addr` failed to answer one of our queries.
+	pub fn failed(&mut self, addr: &SocketAddr) {
+		if let Some(node) = self.find_by_addr(addr) {
+			node.failures += 1;
+			node.timeouts += 1;
+		}
+	}
+
+	/// Record that the node at `addr` answered one of our queries with an error.
+	pub fn errored(&mut self, addr: &SocketAddr) {
+		if let Some(node) = self.find_by_addr(addr) {
+			node.errors += 1;
+		}
+	}
+
+	/// Evict pinged nodes that didn't answer in time, in favour of the node
+	/// that was waiting for their slot.
+	pub fn expire_pending(&mut self) {
+		let now = Instant::now();
+		for bucket in self.buckets.iter_mut() {
+			let Some(pending) = bucket.pending.take_if(|p| now.duration_since(p.since) >= PING_TIMEOUT)
+			else {
+				continue;
+			};
+			if let Some(node) = bucket.nodes.iter_mut().find(|n| n.id == pending.pinged) {
+				if node.last_response.is_none_or(|t| t < pending.since) {
+					*node = pending.candidate;
+					bucket.last_changed = now;
+				}
+			}
+		}
+	}
+
+	/// The `n` non-bad nodes closest to `target`, nearest first.
+	pub fn closest(&self, target: &[u8; 20], n: usize) -> Vec<RoutingNode> {
+		let now = Instant::now();
+		let mut nodes: Vec<_> = self
+			.buckets
+			.iter()
+			.flat_map(|b| b.nodes.iter())
+			.filter(|n| n.status(now) != NodeStatus::Bad)
+			.cloned()
+			.collect();
+		nodes.sort_by_key(|n| xor_distance(&n.id, target));
+		nodes.truncate(n);
+		nodes
+	}
+
+	/// Indexes of non-empty buckets that have been quiet for too long.
+	///
+	/// Returned buckets are marked as refreshed.
+	pub fn take_stale_buckets(&mut self) -> Vec<usize> {
+		let now = Instant::now();
+		let mut stale = Vec::new();
+		for (i, bucket) in self.buckets.iter_mut().enumerate() {
+			if !bucket.nodes.is_empty() && now.duration_since(bucket.last_changed) >= REFRESH_AFTER {
+				bucket.last_changed = now;
+				stale.push(i);
+			}
+		}
+		stale
+	}
+
+	/// Indexes of empty buckets farther from us than our closest node.
+	pub fn empty_far_buckets(&self) -> Vec<usize> {
+		let closest = self.buckets.iter().rposition(|b| !b.nodes.is_empty()).unwrap_or(0);
+		(0..closest).filter(|&i| self.buckets[i].nodes.is_empty()).collect()
+	}
+
+	/// A random id that falls in bucket `index`.
+	pub fn random_id_in_bucket(&self, index: usize) -> [u8; 20] {
+		let mut id = [0u8; 20];
+		thread_rng().fill_bytes(&mut id);
+		for bit in 0..=index.min(159) {
+			let (byte, mask) = (bit / 8, 0x80 >> (bit % 8));
+			let want = if bit == index { !self.self_id[byte] } else { self.self_id[byte] };
+			id[byte] = (id[byte] & !mask) | (want & mask);
+		}
+		id
+	}
+
+	pub fn nodes(&self) -> impl Iterator<Item = &RoutingNode> {
+		self.buckets.iter().flat_map(|b| b.nodes.iter())
+	}
+
+	pub fn len(&self) -> usize {
+		self.buckets.iter().map(|b| b.nodes.len()).sum()
+	}
+
+	/// Good, questionable and bad node counts for each non-empty bucket.
+	pub fn stats(&self) -> Vec<(usize, BucketStats)> {
+		let now = Instant::now();
+		self
+			.buckets
+			.iter()
+			.enumerate()
+			.filter(|(_, b)| !b.nodes.is_empty())
+			.map(|(i, b)| {
+				let mut stats = BucketStats::default();
+				for node in &b.nodes {
+					match node.status(now) {
+						NodeStatus::Good => stats.good += 1,
+						NodeStatus::Questionable => stats.questionable += 1,
+						NodeStatus::Bad => stats.bad += 1,
+					}
+					if !node.compliant {
+						stats.forged += 1;
+					}
+				}
+				(i, stats)
+			})
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn addr(i: u32) -> SocketAddr {
+		SocketAddr::from((std::net::Ipv4Addr::from(0x0a00_0000 + i), 6881))
+	}
+
+	/// An id starting with `prefix`, then `i`, then zeros.
+	fn id(prefix: &[u8], i: u8) -> [u8; 20] {
+		let mut id = [0; 20];
+		id[..prefix.len()].copy_from_slice(prefix);
+		id[19] = i;
+		id
+	}
+
+	fn table() -> RoutingTable {
+		RoutingTable::new([0; 20], NodeIdPolicy::Ignore)
+	}
+
+	#[test]
+	fn nodes_go_in_the_bucket_of_their_shared_prefix() {
+		let table = table();
+		assert_eq!(table.bucket_index(&id(&[0x80], 0)), Some(0));
+		assert_eq!(table.bucket_index(&id(&[0x01], 0)), Some(7));
+		assert_eq!(table.bucket_index(&id(&[0, 0x40], 0)), Some(9));
+		assert_eq!(table.bucket_index(&id(&[], 1)), Some(159));
+		assert_eq!(table.bucket_index(&[0; 20]), None, "our own id");
+		for index in [0, 1, 8, 63, 159] {
+			assert_eq!(table.bucket_index(&table.random_id_in_bucket(index)), Some(index));
+		}
+	}
+
+	#[test]
+	fn full_buckets_only_take_nodes_in_place_of_unresponsive_ones() {
+		let mut table = table();
+		for i in 0..K as u8 {
+			table.load(id(&[0x80], i), addr(i.into()), None);
+		}
+		// A closer bucket still has room.
+		assert!(table.heard_from(id(&[0x40], 0), addr(100), Heard::Response).is_none());
+
+		let pinged = table.heard_from(id(&[0x80], 100), addr(101), Heard::Response).unwrap();
+		assert_eq!(pinged.id, id(&[0x80], 0));
+		assert!(table.heard_from(id(&[0x80], 101), addr(102), Heard::Response).is_none());
+		assert_eq!(table.len(), K + 1);
+
+		table.buckets[0].pending.as_mut().unwrap().since -= PING_TIMEOUT;
+		table.expire_pending();
+		let bucket: Vec<_> = table.buckets[0].nodes.iter().map(|node| node.id).collect();
+		assert!(bucket.contains(&id(&[0x80], 100)) && !bucket.contains(&pinged.id));
+
+		// A pinged node that answers keeps its slot.
+		let pinged = table.heard_from(id(&[0x80], 102), addr(103), Heard::Response).unwrap();
+		table.heard_from(pinged.id, pinged.addr, Heard::Response);
+		table.expire_pending();
+		assert!(table.buckets[0].nodes.iter().any(|node| node.id == pinged.id));
+		assert!(table.buckets[0].pending.is_none());
+	}
+
+	#[test]
+	fn closest_nodes_are_sorted_by_xor_distance() {
+		let mut table = table();
+		let ids: Vec<[u8; 20]> = (0..200).map(|_| rand::random()).collect();
+		for (i, &id) in ids.iter().enumerate() {
+			table.heard_from(id, addr(i as u32), Heard::Response);
+		}
+		let target = rand::random();
+		let mut known: Vec<_> = table.nodes().map(|node| node.id).collect();
+		known.sort_by_key(|id| xor_distance(id, &target));
+		let closest: Vec<_> = table.closest(&target, K).iter().map(|node| node.id).collect();
+		assert_eq!(closest, known[..K]);
+
+		// Bad nodes are left out.
+		let nearest = table.closest(&target, 1)[0].addr;
+		for _ in 0..MAX_FAILURES {
+			table.failed(&nearest);
+		}
+		let closest: Vec<_> = table.closest(&target, K).iter().map(|node| node.id).collect();
+		assert_eq!(closest, known[1..=K]);
+	}
+}
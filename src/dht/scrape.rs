@@ -0,0 +1,104 @@
+use sha1::{Digest, Sha1};
+use std::net::IpAddr;
+
+/// BEP 33 filters are 256 bytes, i.e. m = 2048 bits, with k = 2 hash functions.
+const BLOOM_BYTES: usize = 256;
+const BLOOM_BITS: usize = BLOOM_BYTES * 8;
+
+/// A BEP 33 bloom filter of peer IPs, as sent in `BFsd` (seeds) and `BFpe` (peers).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BloomFilter([u8; BLOOM_BYTES]);
+
+impl Default for BloomFilter {
+	fn default() -> Self {
+		Self([0; BLOOM_BYTES])
+	}
+}
+
+impl BloomFilter {
+	pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+		Some(Self(bytes.try_into().ok()?))
+	}
+
+	pub fn as_bytes(&self) -> &[u8] {
+		&self.0
+	}
+
+	pub fn insert(&mut self, ip: &IpAddr) {
+		let hash = match ip {
+			IpAddr::V4(ip) => Sha1::digest(ip.octets()),
+			IpAddr::V6(ip) => Sha1::digest(ip.octets()),
+		};
+		for index in
+			[hash[0] as usize | (hash[1] as usize) << 8, hash[2] as usize | (hash[3] as usize) << 8]
+		{
+			let index = index % BLOOM_BITS;
+			self.0[index / 8] |= 1 << (index % 8);
+		}
+	}
+
+	/// Union with another node's filter.
+	pub fn merge(&mut self, other: &Self) {
+		for (a, b) in self.0.iter_mut().zip(other.0.iter()) {
+			*a |= b;
+		}
+	}
+
+	/// Estimated number of distinct IPs inserted, per BEP 33.
+	pub fn estimate(&self) -> f64 {
+		let m = BLOOM_BITS as f64;
+		let zeros = self.0.iter().map(|b| b.count_zeros()).sum::<u32>() as f64;
+		let c = zeros.min(m - 1.0);
+		(c / m).ln() / (2.0 * (1.0 - 1.0 / m).ln())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::net::{Ipv4Addr, Ipv6Addr};
+
+	/// The example from BEP 33: 192.0.2.0 to 192.0.2.255 and 2001:db8:: to 2001:db8::3e7.
+	fn bep33_example() -> BloomFilter {
+		let mut filter = BloomFilter::default();
+		for i in 0..=255 {
+			filter.insert(&IpAddr::V4(Ipv4Addr::new(192, 0, 2, i)));
+		}
+		for i in 0..1000 {
+			filter.insert(&IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i)));
+		}
+		filter
+	}
+
+	#[test]
+	fn bits_match_the_bep33_example() {
+		let expected = concat!(
+			"F6C3F5EAA07FFD91BDE89F777F26FB2BFF37BDB8FB2BBAA2FD3DDDE7BACFFF75EE7CCBAEFE5EEDB1FBFAFF67",
+			"F6ABFF5E43DDBCA3FD9B9FFDF4FFD3E9DFF12D1BDF59DB53DBE9FA5B7FF3B8FDFCDE1AFB8BEDD7BE2F3EE71E",
+			"BBBFE93BCDEEFE148246C2BC5DBFF7E7EFDCF24FD8DC7ADFFD8FFFDFDDFFF7A4BBEEDF5CB95CE81FC7FCFF1F",
+			"F4FFFFDFE5F7FDCBB7FD79B3FA1FC77BFE07FFF905B7B7FFC7FEFEFFE0B8370BB0CD3F5B7F2BD93FEB4386CF",
+			"DD6F7FD5BFAF2E9EBFFFFEECD67ADBF7C67F17EFD5D75EBA6FFEBA7FFF47A91EB1BFBB53E8ABFB5762ABE8FF",
+			"237279BFEFBFEEF5FFC5FEBFDFE5ADFFADFEE1FB737FFFFBFD9F6AEFFEEE76B6FD8F72EF",
+		);
+		assert_eq!(hex::encode_upper(bep33_example().as_bytes()), expected);
+	}
+
+	#[test]
+	fn size_estimate_matches_the_bep33_example() {
+		assert!((bep33_example().estimate() - 1224.9308).abs() < 0.001);
+		assert!(BloomFilter::default().estimate() < 1.0);
+	}
+
+	#[test]
+	fn merged_filters_count_each_ip_once() {
+		let (mut a, mut b) = (BloomFilter::default(), BloomFilter::default());
+		for i in 0..100 {
+			a.insert(&IpAddr::V4(Ipv4Addr::new(10, 0, 0, i)));
+			b.insert(&IpAddr::V4(Ipv4Addr::new(10, 0, 0, i + 50)));
+		}
+		a.merge(&b);
+		assert!((a.estimate() - 150.0).abs() < 15.0, "estimated {}", a.estimate());
+		assert_eq!(BloomFilter::from_bytes(a.as_bytes()), Some(a));
+		assert_eq!(BloomFilter::from_bytes(&[0; 255]), None);
+	}
+}
@@ -0,0 +1,113 @@
+use super::BloomFilter;
+use rand::prelude::*;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// How often the token secret changes; tokens from the previous secret are still accepted.
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+
+/// How long an announced peer is kept, per BEP 5's suggestion.
+const PEER_EXPIRY: Duration = Duration::from_secs(30 * 60);
+
+/// Most peers we remember for one infohash.
+const MAX_PEERS_PER_INFOHASH: usize = 1000;
+
+/// Most infohashes we store peers for.
+const MAX_INFOHASHES: usize = 10_000;
+
+/// Write tokens we hand out in `get_peers` replies and check on `announce_peer`.
+pub struct TokenSecrets {
+	current: [u8; 16],
+	previous: [u8; 16],
+	rotated: Instant,
+}
+
+impl Default for TokenSecrets {
+	fn default() -> Self {
+		Self { current: thread_rng().gen(), previous: thread_rng().gen(), rotated: Instant::now() }
+	}
+}
+
+fn token(secret: &[u8; 16], ip: &IpAddr) -> Vec<u8> {
+	let mut hasher = Sha1::new();
+	match ip {
+		IpAddr::V4(ip) => hasher.update(ip.octets()),
+		IpAddr::V6(ip) => hasher.update(ip.octets()),
+	}
+	hasher.update(secret);
+	hasher.finalize()[..8].to_vec()
+}
+
+impl TokenSecrets {
+	fn rotate_if_due(&mut self) {
+		if self.rotated.elapsed() >= TOKEN_ROTATION {
+			self.previous = self.current;
+			self.current = thread_rng().gen();
+			self.rotated = Instant::now();
+		}
+	}
+
+	pub fn token_for(&mut self, ip: &IpAddr) -> Vec<u8> {
+		self.rotate_if_due();
+		token(&self.current, ip)
+	}
+
+	pub fn verify(&mut self, ip: &IpAddr, candidate: &[u8]) -> bool {
+		self.rotate_if_due();
+		candidate == token(&self.current, ip) || candidate == token(&self.previous, ip)
+	}
+}
+
+/// Peers other nodes have announced to us, and whether they said they were seeds.
+#[derive(Default)]
+pub struct PeerStore {
+	peers: HashMap<[u8; 20], HashMap<SocketAddr, (Instant, bool)>>,
+}
+
+impl PeerStore {
+	pub fn announce(&mut self, info_hash: [u8; 20], addr: SocketAddr, seed: bool) {
+		if !self.peers.contains_key(&info_hash) && self.peers.len() >= MAX_INFOHASHES {
+			self.expire();
+			if self.peers.len() >= MAX_INFOHASHES {
+				return;
+			}
+		}
+		let peers = self.peers.entry(info_hash).or_default();
+		if peers.len() < MAX_PEERS_PER_INFOHASH || peers.contains_key(&addr) {
+			peers.insert(addr, (Instant::now(), seed));
+		}
+	}
+
+	fn live(&self, info_hash: &[u8; 20]) -> impl Iterator<Item = (&SocketAddr, bool)> {
+		let peers = self.peers.get(info_hash).into_iter().flatten();
+		peers.filter(|(_, (t, _))| t.elapsed() < PEER_EXPIRY).map(|(addr, (_, seed))| (addr, *seed))
+	}
+
+	/// Up to `max` live peers for `info_hash`, in random order, leaving out seeds if `noseed`.
+	pub fn get(&self, info_hash: &[u8; 20], max: usize, noseed: bool) -> Vec<SocketAddr> {
+		let live = self.live(info_hash).filter(|(_, seed)| !(noseed && *seed)).map(|(addr, _)| *addr);
+		live.choose_multiple(&mut thread_rng(), max)
+	}
+
+	/// BEP 33 bloom filters of the seeds and of the other peers we have for `info_hash`.
+	pub fn scrape(&self, info_hash: &[u8; 20]) -> (BloomFilter, BloomFilter) {
+		let (mut seeds, mut peers) = (BloomFilter::default(), BloomFilter::default());
+		for (addr, seed) in self.live(info_hash) {
+			if seed { &mut seeds } else { &mut peers }.insert(&addr.ip());
+		}
+		(seeds, peers)
+	}
+
+	pub fn expire(&mut self) {
+		for peers in self.peers.values_mut() {
+			peers.retain(|_, (t, _)| t.elapsed() < PEER_EXPIRY);
+		}
+		self.peers.retain(|_, peers| !peers.is_empty());
+	}
+
+	pub fn len(&self) -> usize {
+		self.peers.len()
+	}
+}
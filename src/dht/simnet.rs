@@ -0,0 +1,292 @@
+use super::{DatagramSocket, PeerListener, PeerStream, Transport};
+use futures::future::BoxFuture;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Ports handed out when binding to port 0.
+const EPHEMERAL_PORTS: std::ops::RangeInclusive<u16> = 49152..=65535;
+
+/// What hosts behind NAT see as their own address.
+const NAT_PRIVATE_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+
+/// Bytes buffered in each direction of a simulated stream.
+const STREAM_BUFFER: usize = 64 * 1024;
+
+/// How a [`SimNetwork`] treats packets.
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+	/// One-way delay of every datagram, and of setting up a stream.
+	pub latency: Duration,
+	/// Up to this much extra delay per datagram, so datagrams can arrive out of order.
+	pub jitter: Duration,
+	/// Chance that a datagram is lost, from 0 to 1.
+	pub loss: f64,
+	/// Seed for jitter and loss, so runs repeat.
+	pub seed: u64,
+}
+
+impl Default for SimConfig {
+	fn default() -> Self {
+		Self { latency: Duration::from_millis(20), jitter: Duration::from_millis(10), loss: 0.0, seed: 0 }
+	}
+}
+
+/// An in-memory network for tests. Get a [`Transport`] for each host with [`SimNetwork::host`]
+/// or [`SimNetwork::nat_host`]; nothing touches real sockets.
+#[derive(Clone)]
+pub struct SimNetwork(Arc<Mutex<SimState>>);
+
+type Inbox = mpsc::UnboundedSender<(Vec<u8>, SocketAddr)>;
+type Backlog = mpsc::UnboundedSender<(Box<dyn PeerStream>, SocketAddr)>;
+
+struct SimState {
+	config: SimConfig,
+	rng: StdRng,
+	/// Datagram sockets, by the public address datagrams to them are sent to.
+	sockets: HashMap<SocketAddr, Inbox>,
+	listeners: HashMap<SocketAddr, Backlog>,
+	/// Public IPs of hosts behind NAT, with the IPs each has sent to. Only those get through.
+	nat: HashMap<IpAddr, HashSet<IpAddr>>,
+	next_port: u16,
+	sent: u64,
+	dropped: u64,
+}
+
+impl SimNetwork {
+	pub fn new(config: SimConfig) -> Self {
+		Self(Arc::new(Mutex::new(SimState {
+			rng: StdRng::seed_from_u64(config.seed),
+			config,
+			sockets: HashMap::new(),
+			listeners: HashMap::new(),
+			nat: HashMap::new(),
+			next_port: *EPHEMERAL_PORTS.start(),
+			sent: 0,
+			dropped: 0,
+		})))
+	}
+
+	/// A host reachable at `ip`.
+	pub fn host(&self, ip: IpAddr) -> SimHost {
+		SimHost { network: self.clone(), ip, nat: false }
+	}
+
+	/// A host behind an address-restricted NAT with public address `ip`: it only receives
+	/// datagrams from IPs it has sent to, and accepts no connections.
+	pub fn nat_host(&self, ip: IpAddr) -> SimHost {
+		self.0.lock().unwrap().nat.insert(ip, HashSet::new());
+		SimHost { network: self.clone(), ip, nat: true }
+	}
+
+	/// Datagrams sent, and datagrams lost, filtered by NAT, or sent to no socket.
+	pub fn stats(&self) -> (u64, u64) {
+		let state = self.0.lock().unwrap();
+		(state.sent, state.dropped)
+	}
+
+	fn send(&self, from: SocketAddr, to: SocketAddr, datagram: Vec<u8>) {
+		let delay = {
+			let mut state = self.0.lock().unwrap();
+			state.sent += 1;
+			if let Some(contacted) = state.nat.get_mut(&from.ip()) {
+				contacted.insert(to.ip());
+			}
+			if state.rng.gen::<f64>() < state.config.loss {
+				state.dropped += 1;
+				return;
+			}
+			let jitter = state.config.jitter.mul_f64(state.rng.gen());
+			state.config.latency + jitter
+		};
+
+		let network = self.clone();
+		tokio::spawn(async move {
+			tokio::time::sleep(delay).await;
+			let mut state = network.0.lock().unwrap();
+			let filtered = state.nat.get(&to.ip()).is_some_and(|contacted| !contacted.contains(&from.ip()));
+			let delivered =
+				!filtered && state.sockets.get(&to).is_some_and(|inbox| inbox.send((datagram, from)).is_ok());
+			if !delivered {
+				state.dropped += 1;
+			}
+		});
+	}
+
+	/// `port`, or a free ephemeral port if it's 0, unless `ip` already uses it.
+	fn allocate_port(state: &mut SimState, ip: IpAddr, port: u16, listener: bool) -> io::Result<u16> {
+		let in_use = |state: &SimState, port| {
+			let addr = SocketAddr::new(ip, port);
+			if listener {
+				state.listeners.contains_key(&addr)
+			} else {
+				state.sockets.contains_key(&addr)
+			}
+		};
+		if port != 0 {
+			if in_use(state, port) {
+				return Err(io::ErrorKind::AddrInUse.into());
+			}
+			return Ok(port);
+		}
+		for _ in EPHEMERAL_PORTS {
+			let port = state.next_port;
+			state.next_port =
+				if port == *EPHEMERAL_PORTS.end() { *EPHEMERAL_PORTS.start() } else { port + 1 };
+			if !in_use(state, port) {
+				return Ok(port);
+			}
+		}
+		Err(io::ErrorKind::AddrInUse.into())
+	}
+}
+
+/// One host on a [`SimNetwork`], with a single IP address.
+#[derive(Clone)]
+pub struct SimHost {
+	network: SimNetwork,
+	ip: IpAddr,
+	nat: bool,
+}
+
+impl SimHost {
+	/// The address other hosts reach us at.
+	pub fn ip(&self) -> IpAddr {
+		self.ip
+	}
+
+	/// The address we see for ourselves, which differs from [`SimHost::ip`] behind NAT.
+	fn local_ip(&self) -> IpAddr {
+		if self.nat {
+			NAT_PRIVATE_IP.into()
+		} else {
+			self.ip
+		}
+	}
+}
+
+impl Transport for SimHost {
+	fn bind_udp(&self, ipv6: bool, port: u16) -> BoxFuture<'_, io::Result<Arc<dyn DatagramSocket>>> {
+		Box::pin(async move {
+			if ipv6 != self.ip.is_ipv6() {
+				return Err(io::Error::new(io::ErrorKind::Unsupported, "no address of that family"));
+			}
+			let mut state = self.network.0.lock().unwrap();
+			let port = SimNetwork::allocate_port(&mut state, self.ip, port, false)?;
+			let (sender, inbox) = mpsc::unbounded_channel();
+			state.sockets.insert(SocketAddr::new(self.ip, port), sender);
+			Ok(Arc::new(SimSocket {
+				network: self.network.clone(),
+				addr: SocketAddr::new(self.ip, port),
+				local_addr: SocketAddr::new(self.local_ip(), port),
+				inbox: tokio::sync::Mutex::new(inbox),
+			}) as Arc<dyn DatagramSocket>)
+		})
+	}
+
+	fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn PeerStream>>> {
+		Box::pin(async move {
+			let latency = self.network.0.lock().unwrap().config.latency;
+			// The SYN and SYN-ACK.
+			tokio::time::sleep(latency * 2).await;
+			let mut state = self.network.0.lock().unwrap();
+			let port = SimNetwork::allocate_port(&mut state, self.ip, 0, true)?;
+			let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+			// Nobody can connect in through NAT.
+			if state.nat.contains_key(&addr.ip()) {
+				return Err(refused);
+			}
+			let backlog = state.listeners.get(&addr).ok_or(refused)?;
+			let (ours, theirs) = tokio::io::duplex(STREAM_BUFFER);
+			backlog
+				.send((Box::new(theirs), SocketAddr::new(self.ip, port)))
+				.map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+			Ok(Box::new(ours) as Box<dyn PeerStream>)
+		})
+	}
+
+	fn listen(&self, ipv6: bool, port: u16) -> BoxFuture<'_, io::Result<Box<dyn PeerListener>>> {
+		Box::pin(async move {
+			if ipv6 != self.ip.is_ipv6() {
+				return Err(io::Error::new(io::ErrorKind::Unsupported, "no address of that family"));
+			}
+			let mut state = self.network.0.lock().unwrap();
+			let port = SimNetwork::allocate_port(&mut state, self.ip, port, true)?;
+			let addr = SocketAddr::new(self.ip, port);
+			let (sender, backlog) = mpsc::unbounded_channel();
+			state.listeners.insert(addr, sender);
+			Ok(Box::new(SimListener {
+				network: self.network.clone(),
+				addr,
+				local_addr: SocketAddr::new(self.local_ip(), port),
+				backlog: tokio::sync::Mutex::new(backlog),
+			}) as Box<dyn PeerListener>)
+		})
+	}
+}
+
+struct SimSocket {
+	network: SimNetwork,
+	/// Where other hosts send to us.
+	addr: SocketAddr,
+	local_addr: SocketAddr,
+	inbox: tokio::sync::Mutex<mpsc::UnboundedReceiver<(Vec<u8>, SocketAddr)>>,
+}
+
+impl DatagramSocket for SimSocket {
+	fn send_to<'a>(&'a self, buf: &'a [u8], addr: SocketAddr) -> BoxFuture<'a, io::Result<usize>> {
+		self.network.send(self.addr, addr, buf.to_vec());
+		Box::pin(futures::future::ready(Ok(buf.len())))
+	}
+
+	fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>> {
+		Box::pin(async move {
+			let Some((datagram, from)) = self.inbox.lock().await.recv().await else {
+				return Err(io::ErrorKind::NotConnected.into());
+			};
+			// Like UDP, anything that doesn't fit is cut off.
+			let len = datagram.len().min(buf.len());
+			buf[..len].copy_from_slice(&datagram[..len]);
+			Ok((len, from))
+		})
+	}
+
+	fn local_addr(&self) -> io::Result<SocketAddr> {
+		Ok(self.local_addr)
+	}
+}
+
+impl Drop for SimSocket {
+	fn drop(&mut self) {
+		self.network.0.lock().unwrap().sockets.remove(&self.addr);
+	}
+}
+
+struct SimListener {
+	network: SimNetwork,
+	addr: SocketAddr,
+	local_addr: SocketAddr,
+	backlog: tokio::sync::Mutex<mpsc::UnboundedReceiver<(Box<dyn PeerStream>, SocketAddr)>>,
+}
+
+impl PeerListener for SimListener {
+	fn accept(&self) -> BoxFuture<'_, io::Result<(Box<dyn PeerStream>, SocketAddr)>> {
+		Box::pin(async move {
+			self.backlog.lock().await.recv().await.ok_or_else(|| io::ErrorKind::NotConnected.into())
+		})
+	}
+
+	fn local_addr(&self) -> io::Result<SocketAddr> {
+		Ok(self.local_addr)
+	}
+}
+
+impl Drop for SimListener {
+	fn drop(&mut self) {
+		self.network.0.lock().unwrap().listeners.remove(&self.addr);
+	}
+}
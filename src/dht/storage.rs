@@ -0,0 +1,114 @@
+use super::InfoDict;
+use sha1::{Digest, Sha1};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// A torrent's files on disk, addressed as one run of bytes. A single-file torrent is stored
+/// at `dir/name`, a multi-file one under the directory `dir/name/`.
+///
+/// All methods block, so call them from `spawn_blocking`.
+#[derive(Debug)]
+pub struct Storage {
+	/// Each file's path and length, in torrent order.
+	files: Vec<(PathBuf, u64)>,
+}
+
+impl Storage {
+	pub fn new(dir: &Path, info: &InfoDict) -> io::Result<Self> {
+		let root = dir.join(safe_name(&info.name)?);
+		let files = match &info.files {
+			None => vec![(root, info.length.unwrap_or_default())],
+			Some(files) => files
+				.iter()
+				.map(|file| {
+					let mut path = root.clone();
+					for part in &file.path {
+						path.push(safe_name(part)?);
+					}
+					Ok((path, file.length))
+				})
+				.collect::<io::Result<_>>()?,
+		};
+		Ok(Self { files })
+	}
+
+	/// The parts of the files that `len` bytes at `offset` fall in: path, offset in the file,
+	/// and length.
+	fn spans(&self, mut offset: u64, mut len: usize) -> io::Result<Vec<(&Path, u64, usize)>> {
+		let mut spans = Vec::new();
+		let mut start = 0;
+		for (path, length) in &self.files {
+			let end = start + length;
+			if len > 0 && offset < end {
+				let n = (end - offset).min(len as u64) as usize;
+				spans.push((path.as_path(), offset - start, n));
+				offset += n as u64;
+				len -= n;
+			}
+			start = end;
+		}
+		if len > 0 {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, "past the end of the torrent"));
+		}
+		Ok(spans)
+	}
+
+	pub fn read(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+		let mut data = vec![0; len];
+		let mut at = 0;
+		for (path, offset, n) in self.spans(offset, len)? {
+			let mut file = File::open(path)?;
+			file.seek(SeekFrom::Start(offset))?;
+			file.read_exact(&mut data[at..at + n])?;
+			at += n;
+		}
+		Ok(data)
+	}
+
+	pub fn write(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+		let mut at = 0;
+		for (path, offset, n) in self.spans(offset, data.len())? {
+			if let Some(parent) = path.parent() {
+				std::fs::create_dir_all(parent)?;
+			}
+			let mut file = OpenOptions::new().write(true).create(true).truncate(false).open(path)?;
+			file.seek(SeekFrom::Start(offset))?;
+			file.write_all(&data[at..at + n])?;
+			at += n;
+		}
+		Ok(())
+	}
+
+	/// Create the torrent's empty files, which no piece will ever write to.
+	pub fn create_empty_files(&self) -> io::Result<()> {
+		for (path, _) in self.files.iter().filter(|(_, length)| *length == 0) {
+			if let Some(parent) = path.parent() {
+				std::fs::create_dir_all(parent)?;
+			}
+			OpenOptions::new().write(true).create(true).truncate(false).open(path)?;
+		}
+		Ok(())
+	}
+
+	/// Which pieces are already on disk and match their hashes, for resuming a download.
+	pub fn recheck(&self, info: &InfoDict) -> Vec<bool> {
+		(0..info.num_pieces())
+			.map(|piece| {
+				let data = self.read(info.piece_offset(piece), info.piece_len(piece));
+				data.is_ok_and(|data| Sha1::digest(&data).as_slice() == info.piece_hash(piece))
+			})
+			.collect()
+	}
+}
+
+/// A file or directory name from the metainfo, refused if it could reach outside the
+/// download directory.
+fn safe_name(name: &str) -> io::Result<&Path> {
+	let path = Path::new(name);
+	let mut components = path.components();
+	match (components.next(), components.next()) {
+		(Some(Component::Normal(_)), None) => Ok(path),
+		_ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsafe file name {name:?}"))),
+	}
+}
@@ -0,0 +1,143 @@
+use super::{ErrorReply, ResponseArgs};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// How long we wait for a node to answer a query.
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+struct PendingRequest {
+	addr: SocketAddr,
+	sender: oneshot::Sender<Reply>,
+}
+
+/// What a node sent back: a response, or a KRPC error.
+pub type Reply = Result<ResponseArgs, ErrorReply>;
+
+/// Outstanding queries, keyed by the transaction id we sent them with.
+#[derive(Default)]
+pub struct Transactions {
+	next: u16,
+	pending: HashMap<u16, PendingRequest>,
+	pub sent: u64,
+	pub answered: u64,
+	/// Answers that were KRPC errors.
+	pub errors: u64,
+	pub timed_out: u64,
+	pub unsolicited: u64,
+}
+
+impl Transactions {
+	/// Allocate a transaction id for a query to `addr`; its reply arrives on the receiver.
+	/// `None` if every id is in use.
+	pub fn start(&mut self, addr: SocketAddr) -> Option<([u8; 2], oneshot::Receiver<Reply>)> {
+		let t =
+			(0..=u16::MAX).map(|i| self.next.wrapping_add(i)).find(|t| !self.pending.contains_key(t))?;
+		self.next = t.wrapping_add(1);
+
+		let (sender, receiver) = oneshot::channel();
+		self.pending.insert(t, PendingRequest { addr, sender });
+		self.sent += 1;
+		Some((t.to_be_bytes(), receiver))
+	}
+
+	/// Deliver a reply to whoever sent the query. Returns `false` if nobody was waiting
+	/// for it from that address.
+	pub fn complete(&mut self, t: &[u8], addr: SocketAddr, reply: Reply) -> bool {
+		let Ok(t) = <[u8; 2]>::try_from(t).map(u16::from_be_bytes) else {
+			self.unsolicited += 1;
+			return false;
+		};
+		match self.pending.get(&t) {
+			Some(pending) if pending.addr == addr => {
+				let pending = self.pending.remove(&t).unwrap();
+				self.answered += 1;
+				if reply.is_err() {
+					self.errors += 1;
+				}
+				pending.sender.send(reply).is_ok()
+			}
+			_ => {
+				self.unsolicited += 1;
+				false
+			}
+		}
+	}
+
+	/// Forget a query that went unanswered.
+	pub fn time_out(&mut self, t: [u8; 2]) {
+		if self.pending.remove(&u16::from_be_bytes(t)).is_some() {
+			self.timed_out += 1;
+		}
+	}
+
+	/// Forget a query that could not be sent.
+	pub fn cancel(&mut self, t: [u8; 2]) {
+		if self.pending.remove(&u16::from_be_bytes(t)).is_some() {
+			self.sent -= 1;
+		}
+	}
+
+	pub fn in_flight(&self) -> usize {
+		self.pending.len()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn addr(port: u16) -> SocketAddr {
+		SocketAddr::from(([10, 0, 0, 1], port))
+	}
+
+	fn error() -> Reply {
+		Err(ErrorReply { code: 201, message: "generic error".into() })
+	}
+
+	#[test]
+	fn pending_ids_are_skipped_and_freed_ones_reused() {
+		let mut transactions = Transactions::default();
+		let (first, _first) = transactions.start(addr(1)).unwrap();
+		let (second, _second) = transactions.start(addr(1)).unwrap();
+		assert_eq!((first, second), ([0, 0], [0, 1]));
+
+		transactions.next = 0;
+		assert_eq!(transactions.start(addr(1)).unwrap().0, [0, 2]);
+		transactions.time_out(first);
+		transactions.next = 0;
+		assert_eq!(transactions.start(addr(1)).unwrap().0, first);
+	}
+
+	#[test]
+	fn running_out_of_ids_is_an_error() {
+		let mut transactions = Transactions::default();
+		let receivers: Vec<_> = (0..=u16::MAX).map(|_| transactions.start(addr(1)).unwrap()).collect();
+		assert!(transactions.start(addr(1)).is_none());
+		transactions.cancel(receivers[1000].0);
+		assert_eq!(transactions.start(addr(1)).unwrap().0, receivers[1000].0);
+	}
+
+	#[test]
+	fn replies_timeouts_and_cancels_are_counted() {
+		let mut transactions = Transactions::default();
+		let (t, mut receiver) = transactions.start(addr(1)).unwrap();
+		// Only the node we asked can answer.
+		assert!(!transactions.complete(&t, addr(2), error()));
+		assert!(!transactions.complete(&[1, 2, 3], addr(1), error()));
+		assert!(transactions.complete(&t, addr(1), error()));
+		assert!(receiver.try_recv().unwrap().is_err());
+		assert!(!transactions.complete(&t, addr(1), error()));
+
+		let (late, _late) = transactions.start(addr(1)).unwrap();
+		transactions.time_out(late);
+		transactions.time_out(late);
+		let (unsent, _unsent) = transactions.start(addr(1)).unwrap();
+		transactions.cancel(unsent);
+
+		assert_eq!(transactions.in_flight(), 0);
+		let Transactions { sent, answered, errors, timed_out, unsolicited, .. } = transactions;
+		assert_eq!((sent, answered, errors, timed_out, unsolicited), (2, 1, 1, 1, 3));
+	}
+}
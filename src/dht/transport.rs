@@ -0,0 +1,143 @@
+use futures::future::BoxFuture;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// A UDP-like socket the DHT sends and receives KRPC packets on.
+pub trait DatagramSocket: Send + Sync {
+	fn send_to<'a>(&'a self, buf: &'a [u8], addr: SocketAddr) -> BoxFuture<'a, io::Result<usize>>;
+	fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>>;
+	fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+/// A TCP-like connection to a peer.
+pub trait PeerStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> PeerStream for T {}
+
+/// Accepts connections from peers.
+pub trait PeerListener: Send + Sync {
+	fn accept(&self) -> BoxFuture<'_, io::Result<(Box<dyn PeerStream>, SocketAddr)>>;
+	fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+/// How a [`Dht`](super::Dht) reaches the network: for real, or through a
+/// [`SimNetwork`](super::SimNetwork).
+pub trait Transport: Send + Sync {
+	/// Bind the DHT socket for IPv4 or IPv6 on `port`; 0 picks a free one.
+	fn bind_udp(&self, ipv6: bool, port: u16) -> BoxFuture<'_, io::Result<Arc<dyn DatagramSocket>>>;
+	fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn PeerStream>>>;
+	/// Listen for peers over IPv4 or IPv6 on `port`; 0 picks a free one.
+	fn listen(&self, ipv6: bool, port: u16) -> BoxFuture<'_, io::Result<Box<dyn PeerListener>>>;
+}
+
+/// The host's network, through tokio sockets, optionally bound to one interface.
+#[derive(Debug, Default)]
+pub struct TokioTransport {
+	interface: Option<String>,
+}
+
+impl TokioTransport {
+	/// Binding to an interface is only supported on Linux.
+	pub fn new(interface: Option<String>) -> io::Result<Self> {
+		#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+		if interface.is_some() {
+			return Err(io::Error::new(io::ErrorKind::Unsupported, "interfaces only supported on Linux"));
+		}
+		Ok(Self { interface })
+	}
+}
+
+impl DatagramSocket for tokio::net::UdpSocket {
+	fn send_to<'a>(&'a self, buf: &'a [u8], addr: SocketAddr) -> BoxFuture<'a, io::Result<usize>> {
+		Box::pin(tokio::net::UdpSocket::send_to(self, buf, addr))
+	}
+
+	fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>> {
+		Box::pin(tokio::net::UdpSocket::recv_from(self, buf))
+	}
+
+	fn local_addr(&self) -> io::Result<SocketAddr> {
+		tokio::net::UdpSocket::local_addr(self)
+	}
+}
+
+impl PeerListener for tokio::net::TcpListener {
+	fn accept(&self) -> BoxFuture<'_, io::Result<(Box<dyn PeerStream>, SocketAddr)>> {
+		Box::pin(async move {
+			let (stream, addr) = tokio::net::TcpListener::accept(self).await?;
+			Ok((Box::new(stream) as Box<dyn PeerStream>, addr))
+		})
+	}
+
+	fn local_addr(&self) -> io::Result<SocketAddr> {
+		tokio::net::TcpListener::local_addr(self)
+	}
+}
+
+impl Transport for TokioTransport {
+	fn bind_udp(&self, ipv6: bool, port: u16) -> BoxFuture<'_, io::Result<Arc<dyn DatagramSocket>>> {
+		Box::pin(async move {
+			let socket = if ipv6 {
+				bind_udp6(port)?
+			} else {
+				tokio::net::UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], port))).await?
+			};
+			#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+			if let Some(interface) = &self.interface {
+				socket.bind_device(Some(interface.as_bytes()))?;
+			}
+			Ok(Arc::new(socket) as Arc<dyn DatagramSocket>)
+		})
+	}
+
+	fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn PeerStream>>> {
+		Box::pin(async move {
+			let socket =
+				if addr.is_ipv6() { tokio::net::TcpSocket::new_v6() } else { tokio::net::TcpSocket::new_v4() }?;
+			#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+			if let Some(interface) = &self.interface {
+				socket.bind_device(Some(interface.as_bytes()))?;
+			}
+			Ok(Box::new(socket.connect(addr).await?) as Box<dyn PeerStream>)
+		})
+	}
+
+	fn listen(&self, ipv6: bool, port: u16) -> BoxFuture<'_, io::Result<Box<dyn PeerListener>>> {
+		Box::pin(async move {
+			let socket = if ipv6 { tcp_socket6()? } else { tokio::net::TcpSocket::new_v4()? };
+			#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+			if let Some(interface) = &self.interface {
+				socket.bind_device(Some(interface.as_bytes()))?;
+			}
+			socket.set_reuseaddr(true)?;
+			let ip: std::net::IpAddr = if ipv6 {
+				std::net::Ipv6Addr::UNSPECIFIED.into()
+			} else {
+				std::net::Ipv4Addr::UNSPECIFIED.into()
+			};
+			socket.bind(SocketAddr::new(ip, port))?;
+			Ok(Box::new(socket.listen(1024)?) as Box<dyn PeerListener>)
+		})
+	}
+}
+
+/// Bind the IPv6 DHT socket. It is v6-only so it can share the port with the IPv4 socket.
+fn bind_udp6(port: u16) -> io::Result<tokio::net::UdpSocket> {
+	use socket2::{Domain, Protocol, Socket, Type};
+	let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+	socket.set_only_v6(true)?;
+	socket.set_nonblocking(true)?;
+	socket.bind(&SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, port)).into())?;
+	tokio::net::UdpSocket::from_std(socket.into())
+}
+
+/// A v6-only TCP socket, so an IPv6 listener can share the port with the IPv4 one.
+fn tcp_socket6() -> io::Result<tokio::net::TcpSocket> {
+	use socket2::{Domain, Protocol, Socket, Type};
+	let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+	socket.set_only_v6(true)?;
+	socket.set_nonblocking(true)?;
+	Ok(tokio::net::TcpSocket::from_std_stream(socket.into()))
+}
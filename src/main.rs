@@ -1,7 +1,5 @@
-mod dht;
-
 use clap::Parser;
-use dht::{Infohash, Node};
+use dht_experiments::dht::{self, Infohash, Node};
 use futures::StreamExt;
 use log::*;
 use std::process::exit;
@@ -14,6 +12,15 @@ struct Args {
 	#[arg(long, default_value_t = false)]
 	sample: bool,
 
+	/// Sample with this many node ids spread evenly over the keyspace instead, on the ports after
+	/// --port; each has its own routing tables and rate limits
+	#[arg(long, default_value_t = 0)]
+	identities: usize,
+
+	/// Estimate swarm sizes of known infohashes with BEP 33 scrapes
+	#[arg(long, default_value_t = false)]
+	scrape: bool,
+
 	/// Harvest metainfo files
 	#[arg(long, default_value_t = false)]
 	harvest: bool,
@@ -25,9 +32,68 @@ struct Args {
 	/// Port to use for DHT
 	#[arg(short, long, default_value_t = 55874)]
 	port: u16,
+
+	/// External IP to derive our node id from, instead of voting on what other nodes report
+	#[arg(long)]
+	external_ip: Option<std::net::IpAddr>,
+
+	/// How to treat nodes whose ids don't match their IP (BEP 42): ignore, prefer or require
+	#[arg(long, default_value_t)]
+	node_id_policy: dht::NodeIdPolicy,
+
+	/// Don't answer queries, and tell other nodes so (BEP 43); for hosts behind NAT
+	#[arg(long, default_value_t = false)]
+	read_only: bool,
+
+	/// Router (host:port) to bootstrap from when we know too few nodes; repeat for several
+	#[arg(long = "router", default_values = dht::DEFAULT_ROUTERS)]
+	routers: Vec<String>,
+
+	/// Most DHT packets we send per second
+	#[arg(long, default_value_t = dht::RateLimits::default().global)]
+	max_pps: f64,
+
+	/// Most DHT packets we send per second to one IP
+	#[arg(long, default_value_t = dht::RateLimits::default().per_ip)]
+	max_pps_per_ip: f64,
+
+	/// Most DHT packets we send per second to one /24 (IPv4) or /48 (IPv6)
+	#[arg(long, default_value_t = dht::RateLimits::default().per_subnet)]
+	max_pps_per_subnet: f64,
+
+	/// BEP 46 magnet link (magnet:?xs=urn:btpk:...) to resolve and fetch metainfo for
+	#[arg(long)]
+	magnet: Option<String>,
+
+	/// Infohash (hex) to publish as a BEP 46 mutable torrent under our key
+	#[arg(long)]
+	publish: Option<String>,
+
+	/// Salt (hex) for --publish, to publish several torrents under one key
+	#[arg(long, default_value_t)]
+	salt: String,
+
+	/// Infohash (hex) of a torrent to download, resuming from data already on disk
+	#[arg(long)]
+	download: Option<String>,
+
+	/// Directory to download torrents into
+	#[arg(long, default_value = ".")]
+	download_dir: std::path::PathBuf,
+
+	/// Priority of each file of --download, comma separated in torrent order: 0 skips a file,
+	/// and higher priority files are fetched first
+	#[arg(long, value_delimiter = ',')]
+	file_priorities: Vec<u8>,
+
+	/// Keep seeding once --download completes, instead of exiting
+	#[arg(long, default_value_t = false)]
+	seed: bool,
 }
 
 static STATUS: Mutex<String> = Mutex::new(String::new());
+/// One line per crawling node.
+static CRAWL_STATUS: Mutex<Vec<String>> = Mutex::new(Vec::new());
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -41,18 +107,101 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 	info!("start");
 
-	dht::launch_dht(args.interface, args.port).await?;
+	let builder = |port| {
+		dht::Dht::builder()
+			.interface(args.interface.clone())
+			.port(port)
+			.external_ip(args.external_ip)
+			.node_id_policy(args.node_id_policy)
+			.read_only(args.read_only)
+			.routers(args.routers.clone())
+			.rate_limits(dht::RateLimits {
+				global: args.max_pps,
+				per_ip: args.max_pps_per_ip,
+				per_subnet: args.max_pps_per_subnet,
+			})
+	};
+
+	let dht = builder(args.port).build().await?;
 
 	info!("dht launched");
 
-	tokio::spawn(async move {
-		loop {
-			let infohash =
-				select!(Infohash "WHERE name IS NULL ORDER BY attempts, RANDOM() LIMIT 1").unwrap();
-			execute!("UPDATE infohash SET attempts = CASE WHEN attempts IS NULL THEN 1 ELSE attempts + 1 END WHERE infohash = " infohash.infohash.unwrap()).unwrap();
-			dbg!(hex::encode(infohash.infohash.unwrap()));
-			let mut s = dht::get_peers(hex::encode(infohash.infohash.unwrap()));
+	if args.sample {
+		let mut crawlers = Vec::new();
+		for slot in 0..args.identities {
+			let port = match args.port {
+				0 => 0,
+				port => {
+					u16::try_from(port as usize + 1 + slot).map_err(|_| "--identities runs past port 65535")?
+				}
+			};
+			// Identities share the node table with `dht`, which looks after it.
+			let builder = builder(port).identity(slot, args.identities).maintain_node_table(false);
+			crawlers.push(builder.build().await?);
+			info!("identity {slot} launched with node id {}", hex::encode(crawlers[slot].self_id()));
+		}
+		if crawlers.is_empty() {
+			crawlers.push(dht.clone());
+		}
+
+		*CRAWL_STATUS.lock().unwrap() = vec![String::new(); crawlers.len()];
+		for (i, crawler) in crawlers.into_iter().enumerate() {
+			tokio::spawn(async move {
+				let prefix = hex::encode(&crawler.self_id()[..2]);
+				let mut s = crawler.crawl();
+				while let Some(status) = s.next().await {
+					match status {
+						Ok(dht::Progress::Progress { status }) => {
+							CRAWL_STATUS.lock().unwrap()[i] = format!("{prefix}: {status}")
+						}
+						Ok(dht::Progress::Complete { .. }) => break,
+						Err(e) => error!("crawl error: {:?}", e),
+					}
+				}
+			});
+		}
+	}
+
+	if args.scrape {
+		let dht = dht.clone();
+		tokio::spawn(async move {
+			loop {
+				let Ok(Some(Infohash { infohash: Some(infohash), .. })) = select!(Option<Infohash>
+					"ORDER BY (SELECT MAX(scraped_ms) FROM scrape WHERE scrape.infohash = infohash.infohash),"
+					"RANDOM() LIMIT 1"
+				) else {
+					// Nothing to scrape until the crawler finds some infohashes.
+					tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+					continue;
+				};
+				let (seeders, leechers) = dht.scrape(infohash).await;
+				*STATUS.lock().unwrap() =
+					format!("scraped {}: ~{seeders} seeders, ~{leechers} leechers", hex::encode(infohash));
+			}
+		});
+	}
+
+	if let Some(info_hash) = &args.publish {
+		let info_hash: [u8; 20] =
+			hex::decode(info_hash)?.try_into().map_err(|_| "--publish takes a 20 byte hex infohash")?;
+		let uri = dht.publish_torrent(dht::publisher_key()?, hex::decode(&args.salt)?, info_hash).await?;
+		info!("published {} as {}", hex::encode(info_hash), uri);
+	}
+
+	if let Some(uri) = args.magnet.clone() {
+		let dht = dht.clone();
+		tokio::spawn(async move {
+			let info_hash = match dht.resolve_magnet(&uri).await {
+				Ok(info_hash) => info_hash,
+				Err(e) => {
+					error!("could not resolve {}: {:?}", uri, e);
+					exit(1);
+				}
+			};
+			info!("{} points at {}", uri, hex::encode(info_hash));
+			execute!("INSERT OR IGNORE INTO infohash(infohash) VALUES (" info_hash ")").unwrap();
 
+			let mut s = dht.get_peers(hex::encode(info_hash));
 			while let Some(status) = s.next().await {
 				if let Ok(dht::Progress::Progress { status }) = status {
 					*STATUS.lock().unwrap() = status;
@@ -60,15 +209,78 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 			}
 
 			info!("complete");
+			exit(0);
+		});
+	}
 
-			if !args.harvest {
-				exit(0);
+	if let Some(info_hash) = args.download.clone() {
+		if !args.file_priorities.is_empty() {
+			let info_hash: [u8; 20] =
+				hex::decode(&info_hash)?.try_into().map_err(|_| "--download takes a 20 byte hex infohash")?;
+			dht.set_file_priorities(info_hash, args.file_priorities.clone());
+		}
+		let dht = dht.clone();
+		let (dir, seed) = (args.download_dir.clone(), args.seed);
+		tokio::spawn(async move {
+			let mut s = dht.download(info_hash, dir);
+			while let Some(status) = s.next().await {
+				match status {
+					Ok(dht::Progress::Progress { status }) => *STATUS.lock().unwrap() = status,
+					Ok(dht::Progress::Complete { status, .. }) => {
+						info!("{}", status);
+						if !seed {
+							exit(0);
+						}
+						*STATUS.lock().unwrap() = format!("{status}; seeding");
+					}
+					Err(e) => {
+						error!("download failed: {:?}", e);
+						exit(1);
+					}
+				}
+			}
+		});
+	}
+
+	tokio::spawn({
+		let dht = dht.clone();
+		async move {
+			let other_mode = args.sample
+				|| args.scrape
+				|| args.magnet.is_some()
+				|| args.publish.is_some()
+				|| args.download.is_some();
+			if other_mode && !args.harvest {
+				return;
+			}
+			loop {
+				let infohash =
+					select!(Infohash "WHERE name IS NULL ORDER BY attempts, RANDOM() LIMIT 1").unwrap();
+				execute!("UPDATE infohash SET attempts = CASE WHEN attempts IS NULL THEN 1 ELSE attempts + 1 END WHERE infohash = " infohash.infohash.unwrap()).unwrap();
+				dbg!(hex::encode(infohash.infohash.unwrap()));
+				let mut s = dht.get_peers(hex::encode(infohash.infohash.unwrap()));
+
+				while let Some(status) = s.next().await {
+					if let Ok(dht::Progress::Progress { status }) = status {
+						*STATUS.lock().unwrap() = status;
+					}
+				}
+
+				info!("complete");
+
+				if !args.harvest {
+					exit(0);
+				}
 			}
 		}
 	});
 
 	let native_options = eframe::NativeOptions::default();
-	eframe::run_native("dht-experiments", native_options, Box::new(|cc| Box::new(MyEguiApp::new(cc))));
+	eframe::run_native(
+		"dht-experiments",
+		native_options,
+		Box::new(|cc| Box::new(MyEguiApp::new(cc, dht))),
+	);
 
 	info!("sleeping!");
 
@@ -79,16 +291,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 use eframe::egui;
 
-#[derive(Default)]
-struct MyEguiApp {}
+struct MyEguiApp {
+	dht: dht::Dht,
+}
 
 impl MyEguiApp {
-	fn new(cc: &eframe::CreationContext<'_>) -> Self {
+	fn new(cc: &eframe::CreationContext<'_>, dht: dht::Dht) -> Self {
 		// Customize egui here with cc.egui_ctx.set_fonts and cc.egui_ctx.set_visuals.
 		// Restore app state using cc.storage (requires the "persistence" feature).
 		// Use the cc.gl (a glow::Context) to create graphics shaders and buffers that you can use
 		// for e.g. egui::PaintCallback.
-		Self::default()
+		Self { dht }
 	}
 }
 
@@ -97,6 +310,28 @@ impl eframe::App for MyEguiApp {
 		egui::CentralPanel::default().show(ctx, |ui| {
 			ui.heading("Hello World!");
 			ui.heading(STATUS.lock().unwrap().as_str());
+			ui.label(CRAWL_STATUS.lock().unwrap().join("\n"));
+			ui.separator();
+			ui.label(format!("external ip: {:?}", self.dht.external_ip()));
+			ui.label(format!(
+				"bootstrap: IPv4 {:?}, IPv6 {:?}",
+				self.dht.bootstrap_status(false),
+				self.dht.bootstrap_status(true)
+			));
+			let (sent, answered, errors, timed_out) = self.dht.transaction_stats();
+			ui.label(format!(
+				"queries: {sent} sent, {answered} answered ({errors} errors), {timed_out} timed out"
+			));
+			let (throttled, dropped) = self.dht.rate_limit_stats();
+			ui.label(format!("rate limit: {throttled} queries delayed, {dropped} replies dropped"));
+			for (family, ipv6) in [("IPv4", false), ("IPv6", true)] {
+				for (i, stats) in self.dht.routing_table_stats(ipv6) {
+					ui.monospace(format!(
+						"{family} bucket {i:3}: {} good, {} questionable, {} bad, {} forged",
+						stats.good, stats.questionable, stats.bad, stats.forged
+					));
+				}
+			}
 		});
 		ctx.request_repaint();
 	}
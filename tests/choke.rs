@@ -0,0 +1,73 @@
+use dht_experiments::dht::{ChokeSlot, Choker, UPLOAD_SLOTS};
+use std::collections::HashSet;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+fn unchoked(slots: &[(u64, Arc<ChokeSlot>)]) -> HashSet<u64> {
+	slots.iter().filter(|(_, slot)| slot.unchoked.load(Ordering::Relaxed)).map(|&(id, _)| id).collect()
+}
+
+#[test]
+fn fastest_interested_peers_get_the_slots() {
+	let mut choker = Choker::default();
+	let slots: Vec<_> = (0..8).map(|_| choker.add()).collect();
+	for (i, (_, slot)) in slots.iter().enumerate() {
+		// Peer 7 is the fastest, but doesn't want anything.
+		slot.interested.store(i != 7, Ordering::Relaxed);
+		slot.downloaded.store(1000 * i as u64, Ordering::Relaxed);
+	}
+
+	choker.rechoke(false);
+	let unchoked = unchoked(&slots);
+	assert_eq!(unchoked.len(), UPLOAD_SLOTS + 1);
+	assert!([3, 4, 5, 6].iter().all(|id| unchoked.contains(id)), "{unchoked:?}");
+	assert!(!unchoked.contains(&7));
+}
+
+#[test]
+fn seeding_ranks_by_upload_and_only_counts_the_last_round() {
+	let mut choker = Choker::default();
+	let slots: Vec<_> = (0..6).map(|_| choker.add()).collect();
+	for (i, (_, slot)) in slots.iter().enumerate() {
+		slot.interested.store(true, Ordering::Relaxed);
+		slot.uploaded.store(1000 * i as u64, Ordering::Relaxed);
+	}
+	choker.rechoke(true);
+	assert!(unchoked(&slots).is_superset(&HashSet::from([2, 3, 4, 5])));
+
+	// Since the last round, only peers 0 to 3 took anything.
+	for (i, (_, slot)) in slots.iter().enumerate().take(4) {
+		slot.uploaded.fetch_add(10_000 + i as u64, Ordering::Relaxed);
+	}
+	choker.rechoke(true);
+	assert!(unchoked(&slots).is_superset(&HashSet::from([0, 1, 2, 3])));
+}
+
+#[test]
+fn optimistic_unchoke_rotates_among_choked_peers() {
+	let mut choker = Choker::default();
+	let slots: Vec<_> = (0..20).map(|_| choker.add()).collect();
+	for (_, slot) in &slots {
+		slot.interested.store(true, Ordering::Relaxed);
+	}
+
+	let mut optimistic = HashSet::new();
+	for _ in 0..30 {
+		choker.rechoke(true);
+		let unchoked = unchoked(&slots);
+		assert_eq!(unchoked.len(), UPLOAD_SLOTS + 1);
+		optimistic.extend(unchoked);
+	}
+	assert!(optimistic.len() > UPLOAD_SLOTS + 2, "only {optimistic:?} were ever unchoked");
+}
+
+#[test]
+fn interested_peers_take_free_slots_at_once() {
+	let mut choker = Choker::default();
+	let slots: Vec<_> = (0..UPLOAD_SLOTS + 2).map(|_| choker.add()).collect();
+	for (id, slot) in &slots {
+		slot.interested.store(true, Ordering::Relaxed);
+		choker.interested(*id);
+	}
+	assert_eq!(unchoked(&slots).len(), UPLOAD_SLOTS + 1);
+}
@@ -0,0 +1,140 @@
+use dht_experiments::dht::{MetaInfo, MAX_METADATA_SIZE, METADATA_PIECE_LEN};
+use sha1::{Digest, Sha1};
+use std::net::SocketAddr;
+
+/// Three pieces' worth of metadata, and a `MetaInfo` waiting for it.
+fn metadata() -> (Vec<u8>, MetaInfo) {
+	let data: Vec<u8> = (0..2 * METADATA_PIECE_LEN + 100).map(|i| (i % 251) as u8).collect();
+	let infohash = Sha1::digest(&data).into();
+	(data, MetaInfo::new(infohash))
+}
+
+fn peer(n: u8) -> SocketAddr {
+	SocketAddr::from(([10, 0, 0, n], 6881))
+}
+
+/// A ut_metadata data message carrying `piece` of `data`.
+fn piece(data: &[u8], piece: usize) -> Vec<u8> {
+	let start = piece * METADATA_PIECE_LEN;
+	let end = (start + METADATA_PIECE_LEN).min(data.len());
+	let header = format!("d8:msg_typei1e5:piecei{piece}e10:total_sizei{}ee", data.len());
+	[header.as_bytes(), &data[start..end]].concat()
+}
+
+fn reject(piece: usize) -> Vec<u8> {
+	format!("d8:msg_typei2e5:piecei{piece}ee").into_bytes()
+}
+
+#[tokio::test]
+async fn metadata_size_is_capped_and_must_agree() {
+	let (data, metainfo) = metadata();
+	assert!(metainfo.got_size(peer(1), MAX_METADATA_SIZE + 1).await.is_err());
+	assert!(metainfo.got_size(peer(1), 0).await.is_err());
+	assert_eq!(metainfo.which_piece(peer(1)).await, None);
+
+	metainfo.got_size(peer(1), data.len()).await.unwrap();
+	metainfo.got_size(peer(2), data.len()).await.unwrap();
+	assert!(metainfo.got_size(peer(3), data.len() + 1).await.is_err());
+}
+
+#[tokio::test]
+async fn malformed_and_unsolicited_messages_are_errors() {
+	let (data, metainfo) = metadata();
+	assert!(metainfo.got_metadata_message(peer(1), &piece(&data, 0)).await.is_err());
+	metainfo.got_size(peer(1), data.len()).await.unwrap();
+
+	assert!(metainfo.got_metadata_message(peer(1), b"garbage").await.is_err());
+	// Not requested yet.
+	assert!(metainfo.got_metadata_message(peer(1), &piece(&data, 0)).await.is_err());
+
+	assert_eq!(metainfo.which_piece(peer(1)).await, Some(0));
+	assert!(metainfo.got_metadata_message(peer(2), &piece(&data, 0)).await.is_err());
+	let wrong_size = format!("d8:msg_typei1e5:piecei0e10:total_sizei{}ee", data.len() + 1);
+	assert!(metainfo.got_metadata_message(peer(1), wrong_size.as_bytes()).await.is_err());
+	let out_of_range = format!("d8:msg_typei1e5:piecei9e10:total_sizei{}ee", data.len());
+	assert!(metainfo.got_metadata_message(peer(1), out_of_range.as_bytes()).await.is_err());
+	let short = &piece(&data, 0)[..100];
+	assert!(metainfo.got_metadata_message(peer(1), short).await.is_err());
+	// Header and data together are longer than a piece, but the data alone is short.
+	let short = &piece(&data, 0)[..METADATA_PIECE_LEN + 10];
+	assert!(metainfo.got_metadata_message(peer(1), short).await.is_err());
+	let long = [piece(&data, 0), vec![0]].concat();
+	assert!(metainfo.got_metadata_message(peer(1), &long).await.is_err());
+
+	assert_eq!(metainfo.got_metadata_message(peer(1), &piece(&data, 0)).await, Ok(false));
+	assert!(!metainfo.waiting_on(peer(1), 0).await);
+}
+
+#[tokio::test]
+async fn rejected_and_dropped_pieces_go_to_other_peers() {
+	let (data, metainfo) = metadata();
+	metainfo.got_size(peer(1), data.len()).await.unwrap();
+	assert_eq!(metainfo.which_piece(peer(1)).await, Some(0));
+	assert_eq!(metainfo.which_piece(peer(2)).await, Some(1));
+	assert_eq!(metainfo.which_piece(peer(3)).await, Some(2));
+	assert_eq!(metainfo.which_piece(peer(4)).await, None);
+
+	assert!(metainfo.got_metadata_message(peer(1), &reject(0)).await.is_err());
+	assert_eq!(metainfo.which_piece(peer(4)).await, Some(0));
+
+	metainfo.release(peer(2)).await;
+	assert!(!metainfo.waiting_on(peer(2), 1).await);
+	assert_eq!(metainfo.which_piece(peer(5)).await, Some(1));
+}
+
+#[tokio::test(start_paused = true)]
+async fn unanswered_requests_time_out() {
+	let (data, metainfo) = metadata();
+	metainfo.got_size(peer(1), data.len()).await.unwrap();
+	for _ in 0..3 {
+		metainfo.which_piece(peer(1)).await.unwrap();
+	}
+	assert_eq!(metainfo.which_piece(peer(2)).await, None);
+
+	tokio::time::advance(std::time::Duration::from_secs(30)).await;
+	assert!(!metainfo.waiting_on(peer(1), 0).await);
+	// The slow peer isn't asked again for what it didn't send.
+	assert_eq!(metainfo.which_piece(peer(1)).await, None);
+	assert_eq!(metainfo.which_piece(peer(2)).await, Some(0));
+}
+
+#[tokio::test]
+async fn a_single_sender_of_bad_metadata_is_banned() {
+	let (data, metainfo) = metadata();
+	metainfo.got_size(peer(1), data.len()).await.unwrap();
+	let mut bad = data.clone();
+	bad[METADATA_PIECE_LEN] ^= 1;
+	for n in 0..3 {
+		assert_eq!(metainfo.which_piece(peer(1)).await, Some(n));
+		assert_eq!(metainfo.got_metadata_message(peer(1), &piece(&bad, n)).await, Ok(false));
+	}
+	assert!(metainfo.is_banned(peer(1)));
+	assert!(!metainfo.is_complete().await);
+	assert_eq!(metainfo.data().await, None);
+}
+
+#[tokio::test]
+async fn bad_pieces_are_blamed_once_the_metadata_verifies() {
+	let (data, metainfo) = metadata();
+	metainfo.got_size(peer(1), data.len()).await.unwrap();
+	let mut bad = data.clone();
+	bad[METADATA_PIECE_LEN] ^= 1;
+
+	// Three peers send a piece each; peer 2's is wrong, but we can't yet tell whose.
+	for n in 0..3 {
+		let (sender, from) = (peer(n as u8 + 1), if n == 1 { &bad } else { &data });
+		assert_eq!(metainfo.which_piece(sender).await, Some(n));
+		assert_eq!(metainfo.got_metadata_message(sender, &piece(from, n)).await, Ok(false));
+	}
+	assert!((1..=3).all(|n| !metainfo.is_banned(peer(n))));
+
+	for n in 0..3 {
+		assert_eq!(metainfo.which_piece(peer(4)).await, Some(n));
+		let complete = metainfo.got_metadata_message(peer(4), &piece(&data, n)).await;
+		assert_eq!(complete, Ok(n == 2));
+	}
+	assert!(metainfo.is_banned(peer(2)));
+	assert!(!metainfo.is_banned(peer(1)) && !metainfo.is_banned(peer(3)));
+	assert!(metainfo.is_complete().await);
+	assert_eq!(metainfo.data().await, Some(data));
+}
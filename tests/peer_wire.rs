@@ -0,0 +1,75 @@
+use dht_experiments::dht::{PeerMessage, PeerState};
+
+#[test]
+fn messages_round_trip() {
+	let messages = [
+		PeerMessage::KeepAlive,
+		PeerMessage::Choke,
+		PeerMessage::Unchoke,
+		PeerMessage::Interested,
+		PeerMessage::NotInterested,
+		PeerMessage::Have { piece: 70000 },
+		PeerMessage::Bitfield(vec![0b1010_0000, 0xff]),
+		PeerMessage::Request { piece: 1, offset: 16384, length: 16384 },
+		PeerMessage::Piece { piece: 1, offset: 16384, data: vec![7; 100] },
+		PeerMessage::Cancel { piece: 1, offset: 16384, length: 16384 },
+		PeerMessage::Extended { id: 0, payload: b"d1:md11:ut_metadatai3eee".to_vec() },
+		PeerMessage::Unknown { id: 13, payload: vec![0, 0, 0, 1] },
+	];
+	for message in messages {
+		let bytes = message.to_bytes();
+		assert_eq!(u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize, bytes.len() - 4);
+		assert_eq!(PeerMessage::from_bytes(&bytes[4..]).unwrap(), message);
+	}
+}
+
+#[test]
+fn malformed_messages_are_rejected() {
+	assert!(PeerMessage::from_bytes(&[4, 0, 0]).is_err());
+	assert!(PeerMessage::from_bytes(&[6, 0, 0, 0, 1]).is_err());
+	assert!(PeerMessage::from_bytes(&[7, 0, 0, 0, 1]).is_err());
+	assert!(PeerMessage::from_bytes(&[20]).is_err());
+}
+
+#[test]
+fn state_follows_the_conversation() {
+	let mut peer = PeerState::new(10);
+	assert!(peer.am_choking && peer.peer_choking && !peer.can_request());
+
+	peer.received(&PeerMessage::Bitfield(vec![0b1000_0000, 0])).unwrap();
+	peer.received(&PeerMessage::Have { piece: 9 }).unwrap();
+	assert!(peer.have.has(0) && !peer.have.has(1) && peer.have.has(9));
+	assert_eq!(peer.have.count(), 2);
+
+	peer.sending(&PeerMessage::Interested);
+	peer.received(&PeerMessage::Unchoke).unwrap();
+	assert!(peer.can_request());
+	peer.received(&PeerMessage::Choke).unwrap();
+	assert!(!peer.can_request());
+
+	peer
+		.received(&PeerMessage::Extended {
+			id: 0,
+			payload: b"d1:md11:ut_metadatai3ee13:metadata_sizei99ee".to_vec(),
+		})
+		.unwrap();
+	assert_eq!((peer.ut_metadata, peer.metadata_size), (Some(3), Some(99)));
+
+	assert!(peer.received(&PeerMessage::Bitfield(vec![0xff])).is_err());
+}
+
+#[test]
+fn pieces_must_fit_the_torrent() {
+	assert!(PeerState::new(10).received(&PeerMessage::Have { piece: 10 }).is_err());
+	assert!(PeerState::new(10).received(&PeerMessage::Have { piece: u32::MAX }).is_err());
+	assert!(PeerState::new(10).received(&PeerMessage::Bitfield(vec![0xff])).is_err());
+	assert!(PeerState::new(10).received(&PeerMessage::Bitfield(vec![0xff, 0xc0, 0])).is_err());
+	// Bits past piece 9 are spare, and must be clear.
+	assert!(PeerState::new(10).received(&PeerMessage::Bitfield(vec![0xff, 0xe0])).is_err());
+	assert!(PeerState::new(10).received(&PeerMessage::Bitfield(vec![0xff, 0xc0])).is_ok());
+
+	// Without the torrent, while fetching metadata, piece announcements are ignored.
+	let mut peer = PeerState::default();
+	peer.received(&PeerMessage::Have { piece: u32::MAX }).unwrap();
+	assert_eq!(peer.have.count(), 0);
+}
@@ -0,0 +1,149 @@
+use dht_experiments::dht::{Bitfield, InfoDict, PiecePicker, BLOCK_SIZE, MAX_PIECE_LENGTH};
+
+/// An info dictionary for files of the given lengths, split into `piece_length` pieces.
+fn info(lengths: &[u64], piece_length: u64) -> InfoDict {
+	let pieces = lengths.iter().sum::<u64>().div_ceil(piece_length) as usize;
+	let mut info = b"d5:filesl".to_vec();
+	for (i, length) in lengths.iter().enumerate() {
+		info.extend(format!("d6:lengthi{length}e4:pathl1:{i}ee").bytes());
+	}
+	info.extend(format!("e4:name1:t12:piece lengthi{piece_length}e6:pieces{}:", pieces * 20).bytes());
+	info.extend(vec![0; pieces * 20]);
+	info.push(b'e');
+	InfoDict::from_bytes(&info).unwrap()
+}
+
+fn bitfield(pieces: impl IntoIterator<Item = u32>) -> Bitfield {
+	let mut bitfield = Bitfield::default();
+	pieces.into_iter().for_each(|piece| bitfield.set(piece));
+	bitfield
+}
+
+/// Ask for blocks for one peer with `peer_has` until there are none left to ask it for.
+fn requests(picker: &mut PiecePicker, peer_has: &Bitfield) -> Vec<(u32, u32, u32)> {
+	let mut requests = Vec::new();
+	while let Some(request) = picker.next_request(peer_has, &requests) {
+		requests.push(request);
+	}
+	requests
+}
+
+/// The pieces of [`requests`], in order.
+fn drain(picker: &mut PiecePicker, peer_has: &Bitfield) -> Vec<u32> {
+	requests(picker, peer_has).into_iter().map(|(piece, _, _)| piece).collect()
+}
+
+const BLOCK: u64 = BLOCK_SIZE as u64;
+
+#[test]
+fn rarest_pieces_come_first() {
+	let info = info(&[4 * BLOCK], BLOCK);
+	let mut picker = PiecePicker::new(&info, vec![false; 4]);
+	picker.peer_bitfield(&bitfield(0..4));
+	picker.peer_bitfield(&bitfield([0, 1, 2]));
+	picker.peer_bitfield(&bitfield([0, 1]));
+
+	assert_eq!(drain(&mut picker, &bitfield(0..4)), [3, 2, 0, 1]);
+}
+
+#[test]
+fn availability_follows_haves_and_departures() {
+	let info = info(&[3 * BLOCK], BLOCK);
+	let mut picker = PiecePicker::new(&info, vec![false; 3]);
+	let seed = bitfield(0..3);
+	picker.peer_bitfield(&seed);
+	picker.peer_has(0);
+	picker.peer_has(0);
+	picker.peer_has(1);
+	picker.peer_gone(&seed);
+	picker.peer_has(2);
+	picker.peer_has(2);
+	picker.peer_has(2);
+
+	// Piece 1 is now had by one peer, piece 0 by two and piece 2 by three.
+	assert_eq!(drain(&mut picker, &seed), [1, 0, 2]);
+}
+
+#[test]
+fn started_pieces_are_finished_before_rarer_ones() {
+	let info = info(&[4 * BLOCK], 2 * BLOCK);
+	let mut picker = PiecePicker::new(&info, vec![false; 2]);
+	let (first, seed) = (bitfield([0]), bitfield([0, 1]));
+	picker.peer_bitfield(&first);
+	picker.peer_bitfield(&seed);
+
+	assert_eq!(picker.next_request(&first, &[]), Some((0, 0, BLOCK_SIZE)));
+	assert_eq!(picker.next_request(&seed, &[]), Some((0, BLOCK_SIZE, BLOCK_SIZE)));
+	assert_eq!(picker.next_request(&seed, &[]), Some((1, 0, BLOCK_SIZE)));
+}
+
+#[test]
+fn higher_priority_files_come_first_and_skipped_files_never() {
+	// File 0 is piece 0, file 1 is pieces 1 to 3.
+	let info = info(&[BLOCK, 3 * BLOCK], BLOCK);
+	let seed = bitfield(0..4);
+
+	let mut picker = PiecePicker::new(&info, vec![false; 4]);
+	picker.peer_bitfield(&seed);
+	picker.peer_bitfield(&bitfield(1..4));
+	picker.set_file_priorities(&[1, 2]);
+	assert_eq!(drain(&mut picker, &seed), [1, 2, 3, 0]);
+
+	let mut picker = PiecePicker::new(&info, vec![false; 4]);
+	picker.set_file_priorities(&[0, 1]);
+	assert_eq!(drain(&mut picker, &seed), [1, 2, 3]);
+	assert_eq!(picker.progress(), (0, 3));
+	for piece in 1..4 {
+		let data = picker.received(piece, 0, &[0; BLOCK_SIZE as usize]).unwrap();
+		assert_eq!(data.len(), BLOCK_SIZE as usize);
+		picker.finished(piece, true);
+	}
+	assert!(picker.is_complete());
+}
+
+#[test]
+fn endgame_duplicates_the_last_requests() {
+	let info = info(&[2 * BLOCK], BLOCK);
+	let mut picker = PiecePicker::new(&info, vec![false; 2]);
+	let seed = bitfield(0..2);
+
+	let slow = requests(&mut picker, &seed);
+	assert_eq!(slow.len(), 2);
+
+	// Everything has been asked for, so a second peer is asked for the same blocks, once each.
+	let mut fast = requests(&mut picker, &seed);
+	fast.sort();
+	assert_eq!(fast, slow);
+
+	let (piece, offset, length) = fast[0];
+	assert!(!picker.is_done(piece, offset));
+	assert!(picker.received(piece, offset, &vec![0; length as usize]).is_some());
+	picker.finished(piece, true);
+	// The slow peer's request can be cancelled; a late answer is ignored.
+	assert!(picker.is_done(piece, offset));
+	assert!(picker.received(piece, offset, &vec![0; length as usize]).is_none());
+}
+
+#[test]
+fn pieces_that_fail_their_hash_are_fetched_again() {
+	let info = info(&[BLOCK + 100], BLOCK);
+	let mut picker = PiecePicker::new(&info, vec![true, false]);
+	let seed = bitfield(0..2);
+
+	assert_eq!(picker.next_request(&seed, &[]), Some((1, 0, 100)));
+	assert!(picker.received(1, 0, &[0; 100]).is_some());
+	picker.finished(1, false);
+	assert_eq!(picker.next_request(&seed, &[]), Some((1, 0, 100)));
+	assert_eq!(picker.progress(), (1, 2));
+}
+
+#[test]
+fn huge_pieces_and_overflowing_lengths_are_invalid() {
+	assert!(info(&[BLOCK], MAX_PIECE_LENGTH as u64).validate().is_ok());
+	assert!(info(&[BLOCK], MAX_PIECE_LENGTH as u64 + 1).validate().is_err());
+	assert!(info(&[BLOCK], 1 << 40).validate().is_err());
+
+	let file = format!("d6:lengthi{}e4:pathl1:fee", i64::MAX);
+	let info = format!("d5:filesl{}e4:name1:t12:piece lengthi16384e6:pieces0:e", file.repeat(3));
+	assert!(InfoDict::from_bytes(info.as_bytes()).unwrap().validate().is_err());
+}
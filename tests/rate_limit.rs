@@ -0,0 +1,20 @@
+use dht_experiments::dht::{RateLimiter, RateLimits};
+use std::net::SocketAddr;
+
+#[tokio::test(start_paused = true)]
+async fn only_the_global_limit_means_congestion() {
+	let mut limiter = RateLimiter::new(RateLimits { global: 10.0, per_ip: 2.0, per_subnet: 10.0 });
+	let busy: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+	assert!(limiter.acquire(&busy).is_ok() && limiter.acquire(&busy).is_ok());
+	assert!(limiter.acquire(&busy).is_err());
+	assert!(!limiter.congested());
+
+	for i in 2..10 {
+		let addr = SocketAddr::from(([10, 0, i, 1], 6881));
+		assert!(limiter.acquire(&addr).is_ok());
+	}
+	assert!(limiter.congested());
+
+	tokio::time::advance(std::time::Duration::from_millis(100)).await;
+	assert!(!limiter.congested());
+}
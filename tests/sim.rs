@@ -0,0 +1,421 @@
+use dht_experiments::dht::{
+	BootstrapStatus, Dht, Handshake, LookupEvent, LookupKind, PeerListener, PeerMessage, PeerStream,
+	Progress, RateLimits, SimConfig, SimNetwork, Transport,
+};
+use futures::StreamExt;
+use sha1::{Digest, Sha1};
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const DHT_PORT: u16 = 6881;
+const PEER_PORT: u16 = 51413;
+
+/// Each host gets its own /24, so the per-subnet rate limit doesn't slow the swarm down.
+fn ip(i: usize) -> IpAddr {
+	Ipv4Addr::new(10, (i / 256) as u8, (i % 256) as u8, 1).into()
+}
+
+async fn node(transport: Arc<dyn Transport>) -> Dht {
+	Dht::builder()
+		.transport(transport)
+		.port(DHT_PORT)
+		.routers(vec![SocketAddr::new(ip(0), DHT_PORT).to_string()])
+		.persistent(false)
+		.build()
+		.await
+		.unwrap()
+}
+
+/// `n` nodes at `ip(0)` to `ip(n - 1)`, each bootstrapped from the first.
+async fn swarm(network: &SimNetwork, n: usize) -> Vec<Dht> {
+	let mut nodes = Vec::new();
+	for i in 0..n {
+		nodes.push(node(Arc::new(network.host(ip(i)))).await);
+	}
+	nodes
+}
+
+fn distance(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+	std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+#[tokio::test(start_paused = true)]
+async fn lookups_find_the_target() {
+	let network = SimNetwork::new(SimConfig::default());
+	let nodes = swarm(&network, 200).await;
+
+	for (from, to) in [(0, 199), (199, 0), (50, 150), (120, 7), (10, 11)] {
+		let target = nodes[to].self_id();
+		let closest = nodes[from].find_node(target, false).await;
+		assert_eq!(closest.first().map(|node| node.id), Some(target), "lookup from {from} for {to}");
+	}
+}
+
+#[tokio::test(start_paused = true)]
+async fn lookups_survive_packet_loss() {
+	let network = SimNetwork::new(SimConfig { loss: 0.1, seed: 1, ..Default::default() });
+	let nodes = swarm(&network, 100).await;
+
+	for (from, to) in [(0, 99), (99, 0), (30, 60)] {
+		let target = nodes[to].self_id();
+		let closest = nodes[from].find_node(target, false).await;
+		let mut ids: Vec<_> =
+			nodes.iter().map(Dht::self_id).filter(|&id| id != nodes[from].self_id()).collect();
+		ids.sort_by_key(|id| distance(id, &target));
+		let found = closest.first().expect("lookup found no nodes").id;
+		assert!(ids[..8].contains(&found), "lookup from {from} for {to} ended far from the target");
+	}
+
+	let (sent, dropped) = network.stats();
+	assert!(dropped > sent / 20, "{dropped} of {sent} datagrams dropped");
+}
+
+#[tokio::test(start_paused = true)]
+async fn finished_lookups_leave_no_requests_outstanding() {
+	let network = SimNetwork::new(SimConfig { loss: 0.1, seed: 2, ..Default::default() });
+	let nodes = swarm(&network, 100).await;
+
+	for (from, to) in [(0, 99), (99, 0), (30, 60)] {
+		nodes[from].find_node(nodes[to].self_id(), false).await;
+		let (sent, answered, errors, timed_out) = nodes[from].transaction_stats();
+		assert_eq!(sent, answered + errors + timed_out, "lookup from {from} for {to}");
+	}
+}
+
+#[tokio::test(start_paused = true)]
+async fn announced_peers_are_found() {
+	let network = SimNetwork::new(SimConfig::default());
+	let nodes = swarm(&network, 100).await;
+	let info_hash = [7; 20];
+
+	let accepted = nodes[3].announce_peer(info_hash, PEER_PORT, false, true).await;
+	assert!(!accepted.is_empty(), "no node accepted the announce");
+
+	let mut peers = HashSet::new();
+	let mut lookup = nodes[90].lookup(info_hash, LookupKind::GetPeers, false);
+	while let Some(LookupEvent::Response { response, .. }) = lookup.next().await {
+		peers.extend(response.values.iter().flatten().filter_map(|peer| peer.addr()));
+	}
+	assert!(peers.contains(&SocketAddr::new(ip(3), PEER_PORT)), "found {peers:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn scrapes_tell_seeds_from_leechers() {
+	let network = SimNetwork::new(SimConfig::default());
+	let nodes = swarm(&network, 100).await;
+	let info_hash = [8; 20];
+
+	assert!(!nodes[3].announce_peer(info_hash, PEER_PORT, false, true).await.is_empty());
+	for leecher in 4..6 {
+		assert!(!nodes[leecher].announce_peer(info_hash, PEER_PORT, false, false).await.is_empty());
+	}
+	assert_eq!(nodes[90].scrape(info_hash).await, (1, 2));
+}
+
+#[tokio::test(start_paused = true)]
+async fn nat_only_lets_contacted_hosts_in() {
+	let network = SimNetwork::new(SimConfig::default());
+	let nodes = swarm(&network, 20).await;
+
+	let natted = node(Arc::new(network.nat_host(ip(1000)))).await;
+	assert!(matches!(natted.bootstrap_status(false), BootstrapStatus::Done(_)));
+	let natted_addr = SocketAddr::new(ip(1000), DHT_PORT);
+
+	// The router answered the natted node's bootstrap queries, so it can reach it.
+	assert!(nodes[0].ping(natted_addr).await.is_ok());
+
+	let stranger = Dht::builder()
+		.transport(Arc::new(network.host(ip(1001))))
+		.routers(Vec::new())
+		.persistent(false)
+		.build()
+		.await
+		.unwrap();
+	assert!(stranger.ping(natted_addr).await.is_err());
+	assert!(network.host(ip(1001)).connect(SocketAddr::new(ip(1000), PEER_PORT)).await.is_err());
+}
+
+#[tokio::test(start_paused = true)]
+async fn identities_spread_over_the_keyspace() {
+	let network = SimNetwork::new(SimConfig::default());
+	let nodes = swarm(&network, 50).await;
+
+	for slot in 0..4 {
+		let identity = Dht::builder()
+			.transport(Arc::new(network.host(ip(100 + slot))))
+			.routers(vec![SocketAddr::new(ip(0), DHT_PORT).to_string()])
+			.persistent(false)
+			.identity(slot, 4)
+			.build()
+			.await
+			.unwrap();
+		assert_eq!(identity.self_id()[0] / 64, slot as u8, "identity {slot} is outside its quarter");
+		assert!(!identity.find_node(nodes[0].self_id(), false).await.is_empty());
+	}
+}
+
+#[tokio::test(start_paused = true)]
+async fn bad_builder_settings_are_errors() {
+	let network = SimNetwork::new(SimConfig::default());
+	for rate in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+		let limits = RateLimits { per_ip: rate, ..Default::default() };
+		let builder = Dht::builder().transport(Arc::new(network.host(ip(1)))).persistent(false);
+		assert!(builder.rate_limits(limits).build().await.is_err(), "rate {rate}");
+	}
+	for (slot, count) in [(0, 0), (4, 4), (5, 2)] {
+		let builder = Dht::builder().transport(Arc::new(network.host(ip(1)))).persistent(false);
+		assert!(builder.identity(slot, count).build().await.is_err(), "slot {slot} of {count}");
+	}
+}
+
+#[tokio::test(start_paused = true)]
+async fn puts_of_invalid_items_are_errors() {
+	let network = SimNetwork::new(SimConfig::default());
+	let nodes = swarm(&network, 20).await;
+
+	assert!(nodes[0].put_immutable(b"not bencoded".to_vec()).await.is_err());
+	assert!(nodes[0].put_immutable(format!("2000:{}", "v".repeat(2000)).into_bytes()).await.is_err());
+	let target = nodes[0].put_immutable(b"5:hello".to_vec()).await.unwrap();
+	assert_eq!(nodes[19].get_immutable(target).await, Some(b"5:hello".to_vec()));
+}
+
+/// Our ut_metadata id, and the one `ExtensionHandshake` advertises for the other side.
+const OUR_UT_METADATA: u8 = 3;
+const THEIR_UT_METADATA: u8 = 2;
+
+async fn send_extended(
+	stream: &mut Box<dyn PeerStream>,
+	id: u8,
+	payload: &[u8],
+) -> std::io::Result<()> {
+	stream.write_u32(payload.len() as u32 + 2).await?;
+	stream.write_all(&[20, id]).await?;
+	stream.write_all(payload).await
+}
+
+/// A test seeder: the info dictionary over BEP 9 ut_metadata, and the torrent's bytes.
+#[derive(Clone, Default)]
+struct Seed {
+	info_hash: [u8; 20],
+	info: Vec<u8>,
+	data: Arc<Vec<u8>>,
+	piece_length: usize,
+	/// Block requests answered, across all connections.
+	requests: Arc<AtomicUsize>,
+}
+
+/// Serve `seed` to everyone who connects.
+async fn serve(listener: Box<dyn PeerListener>, seed: Seed) {
+	while let Ok((stream, _)) = listener.accept().await {
+		tokio::spawn(serve_peer(stream, seed.clone()));
+	}
+}
+
+async fn serve_peer(mut stream: Box<dyn PeerStream>, seed: Seed) -> std::io::Result<()> {
+	let mut handshake = [0; 68];
+	stream.read_exact(&mut handshake).await?;
+	assert_eq!(handshake[28..48], seed.info_hash);
+
+	let mut reply = vec![19];
+	reply.extend_from_slice(b"BitTorrent protocol");
+	reply.extend_from_slice(&[0, 0, 0, 0, 0, 0x10, 0, 0]);
+	reply.extend_from_slice(&seed.info_hash);
+	reply.extend_from_slice(&[1; 20]);
+	stream.write_all(&reply).await?;
+	if !seed.data.is_empty() {
+		let pieces = seed.data.len().div_ceil(seed.piece_length);
+		let bitfield = (0..pieces.div_ceil(8))
+			.map(|byte| if byte < pieces / 8 { 0xff } else { !(0xff >> (pieces % 8)) })
+			.collect();
+		stream.write_all(&PeerMessage::Bitfield(bitfield).to_bytes()).await?;
+	}
+	let extensions =
+		format!("d1:md11:ut_metadatai{OUR_UT_METADATA}ee13:metadata_sizei{}ee", seed.info.len());
+	send_extended(&mut stream, 0, extensions.as_bytes()).await?;
+
+	loop {
+		let reply = match PeerMessage::read(&mut stream).await? {
+			// `info` fits in one piece, so every request is for piece 0.
+			PeerMessage::Extended { id: OUR_UT_METADATA, .. } => {
+				let info = &seed.info;
+				let mut payload =
+					format!("d8:msg_typei1e5:piecei0e10:total_sizei{}ee", info.len()).into_bytes();
+				payload.extend_from_slice(info);
+				send_extended(&mut stream, THEIR_UT_METADATA, &payload).await?;
+				continue;
+			}
+			PeerMessage::Interested => PeerMessage::Unchoke,
+			PeerMessage::Request { piece, offset, length } => {
+				seed.requests.fetch_add(1, Ordering::Relaxed);
+				let start = piece as usize * seed.piece_length + offset as usize;
+				let data = seed.data[start..start + length as usize].to_vec();
+				PeerMessage::Piece { piece, offset, data }
+			}
+			_ => continue,
+		};
+		stream.write_all(&reply.to_bytes()).await?;
+	}
+}
+
+#[tokio::test(start_paused = true)]
+async fn metadata_is_fetched_from_announced_peer() {
+	let network = SimNetwork::new(SimConfig::default());
+	let nodes = swarm(&network, 50).await;
+
+	let info = b"d6:lengthi5e4:name5:hello12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+	let info_hash: [u8; 20] = Sha1::digest(info).into();
+
+	let seeder_host = network.host(ip(500));
+	let seeder = node(Arc::new(seeder_host.clone())).await;
+	let listener = seeder_host.listen(false, PEER_PORT).await.unwrap();
+	tokio::spawn(serve(listener, Seed { info_hash, info: info.to_vec(), ..Default::default() }));
+	assert!(!seeder.announce_peer(info_hash, PEER_PORT, false, true).await.is_empty());
+
+	let mut result = None;
+	let mut progress = nodes[25].get_peers(hex::encode(info_hash));
+	while let Some(update) = progress.next().await {
+		if let Progress::Complete { result: found, .. } = update.unwrap() {
+			result = Some(found);
+		}
+	}
+	assert_eq!(result.unwrap().metadata.as_deref(), Some(&info[..]));
+}
+
+/// A torrent of files `a` (10000 bytes) and `dir/b` (30000 bytes) in three pieces: its data
+/// and its info dictionary.
+fn torrent() -> (Vec<u8>, Vec<u8>) {
+	let data: Vec<u8> = (0..40000u32).map(|i| (i * 7 + i / 251) as u8).collect();
+	let mut info = b"d5:filesld6:lengthi10000e4:pathl1:aeed6:lengthi30000e4:pathl3:dir1:beee".to_vec();
+	info.extend_from_slice(b"4:name7:torrent12:piece lengthi16384e6:pieces60:");
+	for piece in data.chunks(16384) {
+		info.extend_from_slice(&Sha1::digest(piece));
+	}
+	info.push(b'e');
+	(data, info)
+}
+
+/// A fresh directory for a test to download into.
+fn temp_dir(name: &str) -> std::path::PathBuf {
+	let dir = std::env::temp_dir().join(format!("dht-{name}-{}", std::process::id()));
+	let _ = std::fs::remove_dir_all(&dir);
+	dir
+}
+
+fn assert_downloaded(dir: &std::path::Path, data: &[u8]) {
+	assert_eq!(std::fs::read(dir.join("torrent/a")).unwrap(), data[..10000]);
+	assert_eq!(std::fs::read(dir.join("torrent/dir/b")).unwrap(), data[10000..]);
+}
+
+/// Run a download to completion.
+async fn download(dht: &Dht, info_hash: [u8; 20], dir: &std::path::Path) {
+	let mut progress = dht.download(hex::encode(info_hash), dir.to_owned());
+	while let Some(update) = progress.next().await {
+		update.unwrap();
+	}
+}
+
+#[tokio::test(start_paused = true)]
+async fn torrent_is_downloaded_and_resumed() {
+	let network = SimNetwork::new(SimConfig::default());
+	let nodes = swarm(&network, 50).await;
+
+	let (data, info) = torrent();
+	let info_hash: [u8; 20] = Sha1::digest(&info).into();
+
+	let seeder_host = network.host(ip(500));
+	let seeder = node(Arc::new(seeder_host.clone())).await;
+	let listener = seeder_host.listen(false, PEER_PORT).await.unwrap();
+	let seed =
+		Seed { info_hash, info, data: Arc::new(data.clone()), piece_length: 16384, ..Default::default() };
+	tokio::spawn(serve(listener, seed.clone()));
+	assert!(!seeder.announce_peer(info_hash, PEER_PORT, false, true).await.is_empty());
+
+	let dir = temp_dir("download");
+	let b = dir.join("torrent/dir/b");
+
+	download(&nodes[25], info_hash, &dir).await;
+	assert_downloaded(&dir, &data);
+	let requests = seed.requests.load(Ordering::Relaxed);
+	assert_eq!(requests, 3);
+
+	// Everything is on disk, so nothing is fetched again.
+	download(&nodes[25], info_hash, &dir).await;
+	assert_eq!(seed.requests.load(Ordering::Relaxed), requests);
+
+	// Only the damaged last piece, a single block, is fetched again.
+	let mut damaged = data[10000..].to_vec();
+	damaged[25000] ^= 1;
+	std::fs::write(&b, damaged).unwrap();
+	download(&nodes[25], info_hash, &dir).await;
+	assert_eq!(seed.requests.load(Ordering::Relaxed), requests + 1);
+	assert_downloaded(&dir, &data);
+
+	std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn downloaders_seed_to_others() {
+	let network = SimNetwork::new(SimConfig::default());
+	let nodes = swarm(&network, 50).await;
+	let (data, info) = torrent();
+
+	let origin_dir = temp_dir("origin");
+	std::fs::create_dir_all(origin_dir.join("torrent/dir")).unwrap();
+	std::fs::write(origin_dir.join("torrent/a"), &data[..10000]).unwrap();
+	std::fs::write(origin_dir.join("torrent/dir/b"), &data[10000..]).unwrap();
+	let origin = node(Arc::new(network.host(ip(500)))).await;
+	let info_hash = origin.seed(info, origin_dir.clone()).await.unwrap();
+	// `seed` announces in the background; make sure that's done before anyone looks.
+	assert!(!origin.announce_peer(info_hash, DHT_PORT, false, true).await.is_empty());
+
+	let first = temp_dir("first");
+	download(&nodes[10], info_hash, &first).await;
+	assert_downloaded(&first, &data);
+
+	// With the origin gone, the first downloader serves the metadata and pieces.
+	drop(origin);
+	tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+	let origin_addr = SocketAddr::new(ip(500), DHT_PORT);
+	assert!(network.host(ip(501)).connect(origin_addr).await.is_err(), "origin still listening");
+
+	let second = temp_dir("second");
+	download(&nodes[40], info_hash, &second).await;
+	assert_downloaded(&second, &data);
+
+	for dir in [origin_dir, first, second] {
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+}
+
+#[tokio::test(start_paused = true)]
+async fn peers_flooding_us_with_requests_are_dropped() {
+	let network = SimNetwork::new(SimConfig::default());
+	let _nodes = swarm(&network, 10).await;
+	let (data, info) = torrent();
+
+	let dir = temp_dir("flooded");
+	std::fs::create_dir_all(dir.join("torrent/dir")).unwrap();
+	std::fs::write(dir.join("torrent/a"), &data[..10000]).unwrap();
+	std::fs::write(dir.join("torrent/dir/b"), &data[10000..]).unwrap();
+	let seeder = node(Arc::new(network.host(ip(500)))).await;
+	let info_hash = seeder.seed(info, dir.clone()).await.unwrap();
+
+	let mut stream = network.host(ip(501)).connect(SocketAddr::new(ip(500), DHT_PORT)).await.unwrap();
+	stream.write_all(&Handshake { info_hash, peer_id: [1; 20] }.to_bytes()).await.unwrap();
+	stream.read_exact(&mut [0; 68]).await.unwrap();
+	stream.write_all(&PeerMessage::Interested.to_bytes()).await.unwrap();
+	while PeerMessage::read(&mut stream).await.unwrap() != PeerMessage::Unchoke {}
+
+	let request = PeerMessage::Request { piece: 0, offset: 0, length: 1 }.to_bytes();
+	stream.write_all(&request.repeat(1000)).await.unwrap();
+	let mut answered = 0;
+	while let Ok(message) = PeerMessage::read(&mut stream).await {
+		answered += matches!(message, PeerMessage::Piece { .. }) as usize;
+	}
+	assert!(answered < 1000, "all {answered} requests were answered");
+
+	std::fs::remove_dir_all(dir).unwrap();
+}